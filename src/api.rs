@@ -1,17 +1,44 @@
 use anyhow::anyhow;
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
-use serde_aux::prelude::deserialize_bool_from_anything;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use serde_aux::prelude::{deserialize_bool_from_anything, deserialize_number_from_string};
 use serde_with::{
-    formats::Strict, rust::string_empty_as_none, serde_as, DisplayFromStr, TimestampSeconds,
+    formats::{Flexible, Strict},
+    rust::string_empty_as_none,
+    serde_as, DeserializeFromStr, DisplayFromStr, OneOrMany, SerializeDisplay, TimestampSeconds,
 };
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
 use std::error::Error;
 use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::raw::{RawPage, RawRecorder};
 
 #[derive(Debug, Clone)]
 pub enum LastFMError {
     AuthError,
     RequestError,
+    PrivateProfile,
+    ApiError(u64, String),
+    /// Replaying raw pages, and the page asked for wasn't saved
+    NotRecorded(String),
+}
+
+impl LastFMError {
+    /// Whether retrying the request might succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            // Operation failed, service offline, temporary error and rate limit
+            LastFMError::ApiError(code, _) => matches!(code, 8 | 11 | 16 | 29),
+            LastFMError::PrivateProfile | LastFMError::NotRecorded(_) => false,
+            _ => true,
+        }
+    }
 }
 
 impl Error for LastFMError {}
@@ -25,36 +52,134 @@ impl fmt::Display for LastFMError {
             LastFMError::RequestError => {
                 write!(f, "Failed to fetch scrobbles.")
             }
+            LastFMError::PrivateProfile => {
+                write!(f, "User's profile is private.")
+            }
+            LastFMError::ApiError(code, message) => {
+                write!(f, "Last.fm returned error {}: {}", code, message)
+            }
+            LastFMError::NotRecorded(request) => {
+                write!(f, "{} isn't in the raw pages.", request)
+            }
         }
     }
 }
 
+// Types are deserialized from Last.fm's field names, and serialized to
+// friendlier ones. Aliases let them be deserialized from the latter too, so
+// that backups can be read back in.
+
 #[serde_as]
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Date {
-    #[serde(rename(deserialize = "#text"))]
+    #[serde(rename(deserialize = "#text"), alias = "pretty_string")]
     pub pretty_string: String,
     #[serde_as(
-        deserialize_as = "TimestampSeconds<String, Strict>",
+        deserialize_as = "TimestampSeconds<String, Flexible>",
         serialize_as = "TimestampSeconds<i64, Strict>"
     )]
-    #[serde(rename(deserialize = "uts", serialize = "timestamp"))]
+    #[serde(
+        rename(deserialize = "uts", serialize = "timestamp"),
+        alias = "timestamp"
+    )]
     pub datetime: DateTime<Utc>,
 }
 
-#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
-#[serde(rename_all = "lowercase")]
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeFromStr, SerializeDisplay)]
 pub enum ImageSize {
     Small,
     Medium,
     Large,
     ExtraLarge,
+    /// A size added since, or none at all, kept as Last.fm sent it
+    Other(String),
 }
 
-#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+impl FromStr for ImageSize {
+    type Err = Infallible;
+
+    fn from_str(size: &str) -> Result<Self, Self::Err> {
+        Ok(match size {
+            "small" => ImageSize::Small,
+            "medium" => ImageSize::Medium,
+            "large" => ImageSize::Large,
+            "extralarge" => ImageSize::ExtraLarge,
+            _ => ImageSize::Other(size.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for ImageSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageSize::Small => write!(f, "small"),
+            ImageSize::Medium => write!(f, "medium"),
+            ImageSize::Large => write!(f, "large"),
+            ImageSize::ExtraLarge => write!(f, "extralarge"),
+            ImageSize::Other(size) => write!(f, "{}", size),
+        }
+    }
+}
+
+/// An item of a page, or why it couldn't be parsed. Items are parsed one at
+/// a time, so that a single unexpected one doesn't cost the whole page.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Parsed<T> {
+    Item(T),
+    Skipped {
+        error: String,
+        /// The item as Last.fm sent it
+        value: serde_json::Value,
+    },
+}
+
+impl<T: DeserializeOwned> Parsed<T> {
+    fn from_value(value: serde_json::Value) -> Self {
+        match T::deserialize(&value) {
+            Ok(item) => Parsed::Item(item),
+            Err(e) => Parsed::Skipped {
+                error: e.to_string(),
+                value,
+            },
+        }
+    }
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for Parsed<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        serde_json::Value::deserialize(deserializer).map(Parsed::from_value)
+    }
+}
+
+/// Deserializes the items of a page, which Last.fm sends as a bare object
+/// instead of a list when there's only one.
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<Parsed<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Array(values) => values.into_iter().map(Parsed::from_value).collect(),
+        serde_json::Value::Null => Vec::new(),
+        value => vec![Parsed::from_value(value)],
+    })
+}
+
+impl<T: Serialize> Serialize for Parsed<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Parsed::Item(item) => item.serialize(serializer),
+            Parsed::Skipped { value, .. } => value.serialize(serializer),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Image {
     #[serde(
         rename(deserialize = "#text"),
+        alias = "url",
+        default,
         with = "string_empty_as_none",
         skip_serializing_if = "Option::is_none"
     )]
@@ -64,44 +189,73 @@ pub struct Image {
 
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Artist {
-    #[serde(rename(deserialize = "#text"))]
+    #[serde(rename(deserialize = "#text"), alias = "name")]
     pub name: String,
-    #[serde(with = "string_empty_as_none", skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        with = "string_empty_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub mbid: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Album {
-    #[serde(rename(deserialize = "#text"))]
+    #[serde(rename(deserialize = "#text"), alias = "name")]
     pub name: String,
-    #[serde(with = "string_empty_as_none", skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        with = "string_empty_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub mbid: Option<String>,
 }
 
-#[serde_as]
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
 pub struct TrackAttributes {
-    #[serde_as(deserialize_as = "DisplayFromStr")]
-    #[serde(rename(deserialize = "nowplaying"))]
+    #[serde(
+        rename(deserialize = "nowplaying"),
+        alias = "now_playing",
+        deserialize_with = "deserialize_bool_from_anything"
+    )]
     pub now_playing: bool,
 }
 
+#[serde_as]
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Track {
-    #[serde(rename(deserialize = "@attr"), skip_serializing_if = "Option::is_none")]
+    #[serde(
+        rename(deserialize = "@attr"),
+        alias = "attributes",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub attributes: Option<TrackAttributes>,
     pub artist: Artist,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub album: Option<Album>,
     pub name: String,
+    #[serde_as(deserialize_as = "OneOrMany<_>")]
+    #[serde(default)]
     pub image: Vec<Image>, // TODO: Skip images that don't contain URLs
     #[serde(skip_serializing_if = "Option::is_none")]
     pub date: Option<Date>,
     pub url: String,
-    #[serde(with = "string_empty_as_none", skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        with = "string_empty_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub mbid: Option<String>,
 }
 
+impl Track {
+    /// Whether this is the track currently being listened to rather than a
+    /// finished scrobble.
+    pub fn is_now_playing(&self) -> bool {
+        matches!(&self.attributes, Some(attributes) if attributes.now_playing)
+    }
+}
+
 #[serde_as]
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
 pub struct RequestAttributes {
@@ -123,8 +277,12 @@ pub struct RequestAttributes {
 pub struct RecentTracks {
     #[serde(rename(deserialize = "@attr"))]
     pub attributes: RequestAttributes,
-    #[serde(rename(deserialize = "track"))]
-    pub tracks: Vec<Track>,
+    #[serde(
+        rename(deserialize = "track"),
+        default,
+        deserialize_with = "one_or_many"
+    )]
+    pub tracks: Vec<Parsed<Track>>,
 }
 
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -136,21 +294,38 @@ pub struct RecentTracksResponse {
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct LovedArtist {
     pub name: String,
-    #[serde(with = "string_empty_as_none", skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        with = "string_empty_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub mbid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
 }
 
+#[serde_as]
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct LovedTrack {
-    #[serde(rename(deserialize = "@attr"), skip_serializing_if = "Option::is_none")]
+    #[serde(
+        rename(deserialize = "@attr"),
+        alias = "attributes",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub attributes: Option<TrackAttributes>,
     pub artist: LovedArtist,
     pub name: String,
+    #[serde_as(deserialize_as = "OneOrMany<_>")]
+    #[serde(default)]
     pub image: Vec<Image>, // TODO: Skip images that don't contain URLs
     #[serde(skip_serializing_if = "Option::is_none")]
     pub date: Option<Date>,
     pub url: String,
-    #[serde(with = "string_empty_as_none", skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        with = "string_empty_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub mbid: Option<String>,
 }
 
@@ -158,8 +333,12 @@ pub struct LovedTrack {
 pub struct LovedTracks {
     #[serde(rename(deserialize = "@attr"))]
     pub attributes: RequestAttributes,
-    #[serde(rename(deserialize = "track"))]
-    pub tracks: Vec<LovedTrack>,
+    #[serde(
+        rename(deserialize = "track"),
+        default,
+        deserialize_with = "one_or_many"
+    )]
+    pub tracks: Vec<Parsed<LovedTrack>>,
 }
 
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -169,28 +348,41 @@ pub struct LovedTracksResponse {
 }
 
 #[serde_as]
-#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct RegisterDate {
-    #[serde(rename(deserialize = "#text"))]
+    #[serde(rename(deserialize = "#text"), alias = "pretty_string")]
     pub pretty_string: String,
     #[serde_as(
-        deserialize_as = "TimestampSeconds<String, Strict>",
+        deserialize_as = "TimestampSeconds<String, Flexible>",
         serialize_as = "TimestampSeconds<i64, Strict>"
     )]
-    #[serde(rename(deserialize = "unixtime", serialize = "timestamp"))]
+    #[serde(
+        rename(deserialize = "unixtime", serialize = "timestamp"),
+        alias = "timestamp"
+    )]
     pub datetime: DateTime<Utc>,
 }
 
-#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Friend {
     pub name: String,
+    #[serde_as(deserialize_as = "OneOrMany<_>")]
+    #[serde(default)]
     pub image: Vec<Image>, // TODO: Skip images that don't contain URLs
-    pub country: String,
+    #[serde(
+        default,
+        with = "string_empty_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub country: Option<String>,
     pub url: String,
-    #[serde(deserialize_with = "deserialize_bool_from_anything")]
+    #[serde(default, deserialize_with = "deserialize_bool_from_anything")]
     pub subscriber: bool,
     #[serde(
         rename(deserialize = "realname"),
+        alias = "real_name",
+        default,
         with = "string_empty_as_none",
         skip_serializing_if = "Option::is_none"
     )]
@@ -202,8 +394,12 @@ pub struct Friend {
 pub struct Friends {
     #[serde(rename(deserialize = "@attr"))]
     pub attributes: RequestAttributes,
-    #[serde(rename(deserialize = "user"))]
-    pub friends: Vec<Friend>,
+    #[serde(
+        rename(deserialize = "user"),
+        default,
+        deserialize_with = "one_or_many"
+    )]
+    pub friends: Vec<Parsed<Friend>>,
 }
 
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -211,25 +407,197 @@ pub struct FriendsResponse {
     friends: Friends,
 }
 
+/// A single page of a paginated response.
+pub trait Page: DeserializeOwned {
+    type Item;
+
+    fn attributes(&self) -> &RequestAttributes;
+    fn into_items(self) -> Vec<Parsed<Self::Item>>;
+}
+
+impl Page for RecentTracksResponse {
+    type Item = Track;
+
+    fn attributes(&self) -> &RequestAttributes {
+        &self.recent_tracks.attributes
+    }
+
+    fn into_items(self) -> Vec<Parsed<Track>> {
+        self.recent_tracks.tracks
+    }
+}
+
+impl Page for LovedTracksResponse {
+    type Item = LovedTrack;
+
+    fn attributes(&self) -> &RequestAttributes {
+        &self.loved_tracks.attributes
+    }
+
+    fn into_items(self) -> Vec<Parsed<LovedTrack>> {
+        self.loved_tracks.tracks
+    }
+}
+
+impl Page for FriendsResponse {
+    type Item = Friend;
+
+    fn attributes(&self) -> &RequestAttributes {
+        &self.friends.attributes
+    }
+
+    fn into_items(self) -> Vec<Parsed<Friend>> {
+        self.friends.friends
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Tag {
+    pub name: String,
+    #[serde(
+        rename(deserialize = "count"),
+        alias = "weight",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub weight: u32,
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TopTags {
+    #[serde(rename(deserialize = "tag"), default, deserialize_with = "one_or_many")]
+    pub tags: Vec<Parsed<Tag>>,
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TopTagsResponse {
+    #[serde(rename(deserialize = "toptags"))]
+    pub top_tags: TopTags,
+}
+
+/// Spaces requests out evenly. Clones share the same schedule, so every
+/// client holding one stays below the limit together.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    interval: Duration,
+    next_request: Arc<Mutex<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_second: u32) -> Self {
+        RateLimiter {
+            interval: Duration::from_secs(1) / requests_per_second,
+            next_request: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Blocks until the next request may be sent.
+    pub fn wait(&self) {
+        let mut next_request = self.next_request.lock().unwrap();
+        let now = Instant::now();
+        if *next_request > now {
+            thread::sleep(*next_request - now);
+        }
+        *next_request = (*next_request).max(now) + self.interval;
+    }
+}
+
 pub struct LastFM {
     http_client: reqwest::blocking::Client,
+    rate_limiter: RateLimiter,
     endpoint: String,
     api_key: String,
     api_secret: String,
     session_key: Option<String>,
+    /// Saves every response as it arrives
+    recorder: Option<Mutex<RawRecorder>>,
+    /// Answers requests from saved responses instead of Last.fm
+    replay: Option<Arc<HashMap<String, String>>>,
+    /// How many items couldn't be parsed and were left out
+    skipped: AtomicUsize,
 }
 
 impl LastFM {
     pub fn new(api_key: &str, api_secret: &str) -> Self {
         LastFM {
             http_client: reqwest::blocking::Client::new(),
+            // Last.fm asks for no more than five requests per second
+            rate_limiter: RateLimiter::new(5),
             endpoint: "http://ws.audioscrobbler.com/2.0".to_string(),
             api_key: api_key.to_owned(),
             api_secret: api_secret.to_owned(),
             session_key: None,
+            recorder: None,
+            replay: None,
+            skipped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Creates a client that never touches the network, answering every
+    /// request from `pages` (see [`crate::raw::index_pages`]) instead.
+    pub fn replay(pages: HashMap<String, String>) -> Self {
+        LastFM {
+            replay: Some(Arc::new(pages)),
+            ..LastFM::new("", "")
         }
     }
 
+    /// Creates a client using different credentials that shares this one's
+    /// connection pool and rate limit, and replays the same pages if this
+    /// one does. Nothing it fetches is recorded.
+    pub fn with_credentials(&self, api_key: &str, api_secret: &str) -> Self {
+        LastFM {
+            http_client: self.http_client.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            endpoint: self.endpoint.clone(),
+            api_key: api_key.to_owned(),
+            api_secret: api_secret.to_owned(),
+            session_key: None,
+            recorder: None,
+            replay: self.replay.clone(),
+            skipped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Saves every response from now on to `recorder`.
+    pub fn record(&mut self, recorder: RawRecorder) {
+        self.recorder = Some(Mutex::new(recorder));
+    }
+
+    /// Stops saving responses, handing back the recorder to be finished.
+    pub fn stop_recording(&mut self) -> Option<RawRecorder> {
+        self.recorder
+            .take()
+            .map(|recorder| recorder.into_inner().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// How many items this client has left out so far because they couldn't
+    /// be parsed. Each one is logged as it's skipped.
+    pub fn skipped_items(&self) -> usize {
+        self.skipped.load(Ordering::Relaxed)
+    }
+
+    /// Keeps the items that parsed, logging the rest along with where they
+    /// came from.
+    fn keep_parsed<T>(&self, items: Vec<Parsed<T>>, source: &str) -> Vec<T> {
+        let mut kept = Vec::with_capacity(items.len());
+        for (i, item) in items.into_iter().enumerate() {
+            match item {
+                Parsed::Item(item) => kept.push(item),
+                Parsed::Skipped { error, value } => {
+                    self.skipped.fetch_add(1, Ordering::Relaxed);
+                    log::warn!(
+                        "Skipping item {} of {}, which couldn't be parsed: {}. It was: {}",
+                        i + 1,
+                        source,
+                        error,
+                        value
+                    );
+                }
+            }
+        }
+        kept
+    }
+
     fn get_signature(&self, mut query: Vec<(String, String)>) -> String {
         query.sort_by_key(|e| e.0.clone());
 
@@ -263,6 +631,7 @@ impl LastFM {
             .get(format!("{}/", self.endpoint))
             .query(&query);
         let req = req.build()?;
+        self.rate_limiter.wait();
         self.http_client.execute(req)
     }
 
@@ -277,6 +646,7 @@ impl LastFM {
             .post(format!("{}/", self.endpoint))
             .form(&query);
         let req = req.build()?;
+        self.rate_limiter.wait();
         self.http_client.execute(req)
     }
 
@@ -299,143 +669,95 @@ impl LastFM {
         Ok(())
     }
 
-    pub fn recent_tracks(&mut self, username: &str) -> anyhow::Result<Vec<Track>> {
-        let mut tracks: Vec<Track> = Vec::new();
-        let mut page = 1;
-        let mut total_pages = 0;
-        let mut failures = 0;
-
-        loop {
-            let mut success = false;
-            log::info!(
-                "Requesting page {} of {}",
-                page,
-                match total_pages {
-                    0 => "?".to_string(),
-                    _ => total_pages.to_string(),
-                }
-            );
-            if let Ok(resp) = self.get(
-                "user.getRecentTracks",
-                vec![
-                    ("user".to_string(), username.to_string()),
-                    ("limit".to_string(), "200".to_string()),
-                    ("page".to_string(), page.to_string()),
-                ],
-            ) {
-                if let Ok(response) = resp.json::<RecentTracksResponse>() {
-                    tracks.extend(response.recent_tracks.tracks);
-
-                    success = true;
-                    failures = 0;
-                    page += 1;
-
-                    let new_total_pages = response.recent_tracks.attributes.total_pages;
-                    match new_total_pages.cmp(&total_pages) {
-                        std::cmp::Ordering::Greater => {
-                            total_pages = new_total_pages;
-                        }
-                        std::cmp::Ordering::Less => {
-                            log::warn!(
-                                "Total pages shrunk from {} to {}. Ignoring",
-                                total_pages,
-                                new_total_pages
-                            );
-                        }
-                        _ => {}
-                    }
-
-                    if page > total_pages {
-                        // TODO: remove true
-                        break Ok(tracks);
-                    }
+    /// Requests a single response, turning errors reported by Last.fm into
+    /// [`LastFMError`]s.
+    fn get_response<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        query: Vec<(String, String)>,
+    ) -> anyhow::Result<T> {
+        let params: BTreeMap<String, String> = query.iter().cloned().collect();
+        let body = match &self.replay {
+            Some(pages) => {
+                let key = RawPage::key(method, &params);
+                match pages.get(&key) {
+                    Some(body) => body.clone(),
+                    None => return Err(anyhow!(LastFMError::NotRecorded(key))),
                 }
             }
-            if !success {
-                failures += 1;
-                if failures < 3 {
-                    log::warn!("Failed to get page. Retrying...");
-                } else {
-                    log::error!("Max retries reached. Aborting.");
-                    break Err(anyhow!(LastFMError::RequestError));
-                }
+            None => self.get(method, query)?.text()?,
+        };
+        if let Some(recorder) = &self.recorder {
+            let page = RawPage {
+                method: method.to_string(),
+                params,
+                fetched: Utc::now(),
+                body: body.clone(),
+            };
+            let mut recorder = recorder.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = recorder.record(&page) {
+                log::error!("Failed to save raw page: {}", e);
             }
         }
+
+        let body: serde_json::Value = serde_json::from_str(&body)?;
+        if let Some(code) = body["error"].as_u64() {
+            let message = body["message"].as_str().unwrap_or_default().to_string();
+            return Err(anyhow!(match code {
+                17 => LastFMError::PrivateProfile,
+                _ => LastFMError::ApiError(code, message),
+            }));
+        }
+        Ok(serde_json::from_value(body)?)
     }
 
-    pub fn loved_tracks(&mut self, username: &str) -> anyhow::Result<Vec<LovedTrack>> {
-        let mut tracks: Vec<LovedTrack> = Vec::new();
-        let mut page = 1;
-        let mut total_pages = 0;
+    /// Requests a response, retrying up to three times unless Last.fm
+    /// reports an error that won't go away by itself.
+    fn get_response_with_retries<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        query: Vec<(String, String)>,
+    ) -> anyhow::Result<T> {
         let mut failures = 0;
 
         loop {
-            let mut success = false;
-            log::info!(
-                "Requesting page {} of {}",
-                page,
-                match total_pages {
-                    0 => "?".to_string(),
-                    _ => total_pages.to_string(),
-                }
-            );
-            if let Ok(resp) = self.get(
-                "user.getLovedTracks",
-                vec![
-                    ("user".to_string(), username.to_string()),
-                    ("limit".to_string(), "200".to_string()),
-                    ("page".to_string(), page.to_string()),
-                ],
-            ) {
-                let response = resp.json::<LovedTracksResponse>();
-                if let Ok(response) = response {
-                    tracks.extend(response.loved_tracks.tracks);
-
-                    success = true;
-                    failures = 0;
-                    page += 1;
-
-                    let new_total_pages = response.loved_tracks.attributes.total_pages;
-                    match new_total_pages.cmp(&total_pages) {
-                        std::cmp::Ordering::Greater => {
-                            total_pages = new_total_pages;
+            match self.get_response(method, query.clone()) {
+                Ok(response) => break Ok(response),
+                Err(e) => {
+                    if let Some(e) = e.downcast_ref::<LastFMError>() {
+                        if !e.is_transient() {
+                            break Err(anyhow!(e.clone()));
                         }
-                        std::cmp::Ordering::Less => {
-                            log::warn!(
-                                "Total pages shrunk from {} to {}. Ignoring...",
-                                total_pages,
-                                new_total_pages
-                            );
-                        }
-                        _ => {}
                     }
-
-                    if page > total_pages {
-                        // TODO: remove true
-                        break Ok(tracks);
+                    failures += 1;
+                    if failures < 3 {
+                        log::warn!("Failed to get page. Retrying...");
+                    } else {
+                        log::error!("Max retries reached. Aborting.");
+                        break Err(anyhow!(LastFMError::RequestError));
                     }
                 }
             }
-            if !success {
-                failures += 1;
-                if failures < 3 {
-                    log::warn!("Failed to get page. Retrying...");
-                } else {
-                    log::error!("Max retries reached. Aborting.");
-                    break Err(anyhow!(LastFMError::RequestError));
-                }
-            }
         }
     }
 
-    pub fn friends(&mut self, username: &str) -> anyhow::Result<Vec<Friend>> {
-        let mut friends: Vec<Friend> = Vec::new();
-        let mut page = 1;
+    /// Requests every page of a paginated method from `first_page` on,
+    /// stopping early once `max_items` items have been collected, and hands
+    /// each page to `on_page` as soon as it arrives.
+    fn get_pages<P: Page>(
+        &self,
+        method: &str,
+        query: Vec<(String, String)>,
+        page_size: usize,
+        first_page: usize,
+        max_items: Option<usize>,
+        mut on_page: impl FnMut(&[P::Item]),
+    ) -> anyhow::Result<Vec<P::Item>> {
+        let mut items: Vec<P::Item> = Vec::new();
+        let mut page = first_page;
         let mut total_pages = 0;
-        let mut failures = 0;
 
         loop {
-            let mut success = false;
             log::info!(
                 "Requesting page {} of {}",
                 page,
@@ -444,51 +766,237 @@ impl LastFM {
                     _ => total_pages.to_string(),
                 }
             );
-            if let Ok(resp) = self.get(
-                "user.getFriends",
-                vec![
-                    ("user".to_string(), username.to_string()),
-                    ("limit".to_string(), "50".to_string()),
-                    ("page".to_string(), page.to_string()),
-                ],
-            ) {
-                if let Ok(response) = resp.json::<FriendsResponse>() {
-                    friends.extend(response.friends.friends);
-
-                    success = true;
-                    failures = 0;
-                    page += 1;
-
-                    let new_total_pages = response.friends.attributes.total_pages;
-                    match new_total_pages.cmp(&total_pages) {
-                        std::cmp::Ordering::Greater => {
-                            total_pages = new_total_pages;
-                        }
-                        std::cmp::Ordering::Less => {
-                            log::warn!(
-                                "Total pages shrunk from {} to {}. Ignoring",
-                                total_pages,
-                                new_total_pages
-                            );
-                        }
-                        _ => {}
-                    }
+            let mut page_query = query.clone();
+            page_query.push(("limit".to_string(), page_size.to_string()));
+            page_query.push(("page".to_string(), page.to_string()));
+            let response: P = self.get_response_with_retries(method, page_query)?;
+
+            let new_total_pages = response.attributes().total_pages;
+            let source = format!("{} page {}", method, page);
+            let mut new_items = self.keep_parsed(response.into_items(), &source);
+            if let Some(max_items) = max_items {
+                new_items.truncate(max_items.saturating_sub(items.len()));
+            }
+            on_page(&new_items);
+            items.extend(new_items);
+            page += 1;
 
-                    if page > total_pages {
-                        // TODO: remove true
-                        break Ok(friends);
-                    }
+            match new_total_pages.cmp(&total_pages) {
+                std::cmp::Ordering::Greater => {
+                    total_pages = new_total_pages;
                 }
+                std::cmp::Ordering::Less => {
+                    log::warn!(
+                        "Total pages shrunk from {} to {}. Ignoring",
+                        total_pages,
+                        new_total_pages
+                    );
+                }
+                _ => {}
             }
-            if !success {
-                failures += 1;
-                if failures < 3 {
-                    log::warn!("Failed to get page. Retrying...");
-                } else {
-                    log::error!("Max retries reached. Aborting.");
-                    break Err(anyhow!(LastFMError::RequestError));
+
+            if let Some(max_items) = max_items {
+                if items.len() >= max_items {
+                    break Ok(items);
                 }
             }
+            if page > total_pages {
+                break Ok(items);
+            }
         }
     }
+
+    pub fn recent_tracks(
+        &mut self,
+        username: &str,
+        max_items: Option<usize>,
+    ) -> anyhow::Result<Vec<Track>> {
+        self.recent_tracks_with(username, max_items, |_| {})
+    }
+
+    /// Like `recent_tracks`, but also hands each page to `on_page` as soon as
+    /// it arrives.
+    pub fn recent_tracks_with(
+        &mut self,
+        username: &str,
+        max_items: Option<usize>,
+        on_page: impl FnMut(&[Track]),
+    ) -> anyhow::Result<Vec<Track>> {
+        self.get_pages::<RecentTracksResponse>(
+            "user.getRecentTracks",
+            vec![("user".to_string(), username.to_string())],
+            200,
+            1,
+            max_items,
+            on_page,
+        )
+    }
+
+    /// Like `recent_tracks_with`, but only fetches scrobbles from before
+    /// `to`, starting at `first_page`. Scrobbles that come in meanwhile don't
+    /// shift the pages, so an interrupted fetch can pick up where it stopped.
+    pub fn recent_tracks_until(
+        &mut self,
+        username: &str,
+        to: DateTime<Utc>,
+        first_page: usize,
+        on_page: impl FnMut(&[Track]),
+    ) -> anyhow::Result<Vec<Track>> {
+        self.get_pages::<RecentTracksResponse>(
+            "user.getRecentTracks",
+            vec![
+                ("user".to_string(), username.to_string()),
+                ("to".to_string(), to.timestamp().to_string()),
+            ],
+            200,
+            first_page,
+            None,
+            on_page,
+        )
+    }
+
+    pub fn loved_tracks(
+        &mut self,
+        username: &str,
+        max_items: Option<usize>,
+    ) -> anyhow::Result<Vec<LovedTrack>> {
+        self.loved_tracks_with(username, max_items, |_| {})
+    }
+
+    /// Like `loved_tracks`, but also hands each page to `on_page` as soon as
+    /// it arrives.
+    pub fn loved_tracks_with(
+        &mut self,
+        username: &str,
+        max_items: Option<usize>,
+        on_page: impl FnMut(&[LovedTrack]),
+    ) -> anyhow::Result<Vec<LovedTrack>> {
+        self.get_pages::<LovedTracksResponse>(
+            "user.getLovedTracks",
+            vec![("user".to_string(), username.to_string())],
+            200,
+            1,
+            max_items,
+            on_page,
+        )
+    }
+
+    pub fn friends(&mut self, username: &str) -> anyhow::Result<Vec<Friend>> {
+        self.get_pages::<FriendsResponse>(
+            "user.getFriends",
+            vec![("user".to_string(), username.to_string())],
+            50,
+            1,
+            None,
+            |_| {},
+        )
+    }
+
+    pub fn artist_top_tags(&mut self, artist: &str) -> anyhow::Result<Vec<Tag>> {
+        let response: TopTagsResponse = self.get_response_with_retries(
+            "artist.getTopTags",
+            vec![
+                ("artist".to_string(), artist.to_string()),
+                ("autocorrect".to_string(), "1".to_string()),
+            ],
+        )?;
+        Ok(self.keep_parsed(response.top_tags.tags, &format!("{}'s tags", artist)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn attributes(total: usize) -> serde_json::Value {
+        json!({
+            "page": "1",
+            "perPage": "50",
+            "total": total.to_string(),
+            "totalPages": "1",
+            "user": "alice",
+        })
+    }
+
+    fn track(name: &str) -> serde_json::Value {
+        json!({
+            "artist": {"#text": "Artist", "mbid": ""},
+            "album": {"#text": "Album", "mbid": ""},
+            "name": name,
+            "image": [{"#text": "https://example.com/small.png", "size": "small"}],
+            "date": {"#text": "01 Jan 2021, 00:00", "uts": "1609459200"},
+            "url": "https://www.last.fm/music/Artist/_/Track",
+            "mbid": "",
+        })
+    }
+
+    fn friend(name: &str) -> serde_json::Value {
+        json!({
+            "name": name,
+            "image": [],
+            "country": "Norway",
+            "url": format!("https://www.last.fm/user/{}", name),
+            "subscriber": "0",
+            "realname": "",
+            "registered": {"#text": "2010-01-01 00:00", "unixtime": "1262304000"},
+        })
+    }
+
+    #[test]
+    fn a_single_item_may_come_as_a_bare_object() {
+        let response: RecentTracksResponse = serde_json::from_value(json!({
+            "recenttracks": {"@attr": attributes(1), "track": track("Only")},
+        }))
+        .unwrap();
+
+        let tracks = response.into_items();
+        assert_eq!(tracks.len(), 1);
+        assert!(matches!(&tracks[0], Parsed::Item(track) if track.name == "Only"));
+    }
+
+    #[test]
+    fn unknown_image_sizes_are_kept_as_sent() {
+        let image: Image = serde_json::from_value(
+            json!({"#text": "https://example.com/huge.png", "size": "huge"}),
+        )
+        .unwrap();
+
+        assert_eq!(image.size, ImageSize::Other("huge".to_string()));
+        assert_eq!(serde_json::to_value(&image).unwrap()["size"], "huge");
+    }
+
+    #[test]
+    fn friends_may_leave_out_their_country() {
+        let mut value = friend("bob");
+        value.as_object_mut().unwrap().remove("country");
+
+        let friend: Friend = serde_json::from_value(value).unwrap();
+        assert_eq!(friend.name, "bob");
+        assert_eq!(friend.country, None);
+    }
+
+    #[test]
+    fn items_that_fail_to_parse_are_skipped_and_the_rest_kept() {
+        let mut broken = friend("carol");
+        broken.as_object_mut().unwrap().remove("registered");
+        let body = json!({
+            "friends": {
+                "@attr": attributes(3),
+                "user": [friend("bob"), broken, friend("dave")],
+            },
+        });
+        let params = [("limit", "50"), ("page", "1"), ("user", "alice")]
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let pages = HashMap::from([(RawPage::key("user.getFriends", &params), body.to_string())]);
+
+        let mut client = LastFM::replay(pages);
+        let friends = client.friends("alice").unwrap();
+
+        let names: Vec<_> = friends.iter().map(|friend| friend.name.as_str()).collect();
+        assert_eq!(names, ["bob", "dave"]);
+        assert_eq!(client.skipped_items(), 1);
+    }
 }
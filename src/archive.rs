@@ -0,0 +1,356 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::api::*;
+use super::encryption::Decryption;
+use super::graph::FriendGraph;
+use super::naming::FileNames;
+use super::serialize::{self, Encoding, Output};
+use super::sink::{Backup, BackupSink, FileFormat, WrittenFile};
+use super::tags::ArtistTags;
+
+/// Bumped whenever the layout of an archive changes in a way older versions
+/// of hatchery can't read.
+pub const FORMAT_VERSION: u32 = 1;
+
+const MANIFEST: &str = "manifest.json";
+
+/// A dataset in an archive.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DatasetInfo {
+    /// Where the dataset is in the archive, if anything of it was fetched
+    pub file: Option<String>,
+    pub count: usize,
+    /// Whether every item was fetched and written
+    pub complete: bool,
+    /// Checksum of the file, checked whenever the archive is read. Missing
+    /// from archives written before checksums were added.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// Describes an archive. Always its first entry, as `manifest.json`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Manifest {
+    pub format_version: u32,
+    pub username: String,
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    pub hatchery_version: String,
+    /// Whether every dataset is complete
+    pub complete: bool,
+    pub datasets: BTreeMap<String, DatasetInfo>,
+}
+
+/// Writes a whole backup to a single `{basename}.tar` holding a manifest
+/// followed by each dataset as JSON. Compressed and encrypted archives are
+/// encoded as a whole, e.g. to `{basename}.tar.gz` or `{basename}.tar.age`.
+///
+/// Nothing is written until the run finishes, so the archive only ever
+/// describes a finished run.
+pub struct ArchiveSink {
+    filename: String,
+    pretty: bool,
+    encoding: Encoding,
+    username: String,
+    started: DateTime<Utc>,
+    files: Vec<(String, Vec<u8>)>,
+    datasets: BTreeMap<String, DatasetInfo>,
+    incomplete: BTreeSet<String>,
+    written: Option<WrittenFile>,
+}
+
+impl ArchiveSink {
+    pub fn new(names: &FileNames, pretty: bool, encoding: Encoding) -> Self {
+        ArchiveSink {
+            filename: names.whole() + ".tar",
+            pretty,
+            encoding,
+            username: String::new(),
+            started: Utc::now(),
+            files: Vec::new(),
+            datasets: BTreeMap::new(),
+            incomplete: BTreeSet::new(),
+            written: None,
+        }
+    }
+
+    fn add<T: Serialize>(&mut self, dataset: &str, data: &[T]) -> anyhow::Result<()> {
+        let bytes = if self.pretty {
+            serde_json::to_vec_pretty(data)?
+        } else {
+            serde_json::to_vec(data)?
+        };
+        let file = format!("{}.json", dataset);
+        let sha256 = format!("{:x}", Sha256::digest(&bytes));
+        self.files.push((file.clone(), bytes));
+        self.datasets.insert(
+            dataset.to_string(),
+            DatasetInfo {
+                file: Some(file),
+                count: data.len(),
+                complete: !self.incomplete.contains(dataset),
+                sha256: Some(sha256),
+            },
+        );
+        Ok(())
+    }
+}
+
+fn append(
+    builder: &mut tar::Builder<Output>,
+    name: &str,
+    data: &[u8],
+    mtime: &DateTime<Utc>,
+) -> anyhow::Result<()> {
+    let mut header = tar::Header::new_ustar();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(mtime.timestamp().max(0) as u64);
+    header.set_cksum();
+    builder.append_data(&mut header, name, data)?;
+    Ok(())
+}
+
+impl BackupSink for ArchiveSink {
+    fn begin_run(&mut self, username: &str) -> anyhow::Result<()> {
+        log::info!("Writing archive...");
+        self.username = username.to_string();
+        self.started = Utc::now();
+        Ok(())
+    }
+
+    fn mark_incomplete(&mut self, dataset: &str) {
+        self.incomplete.insert(dataset.to_string());
+    }
+
+    fn write_loved_tracks(&mut self, loved_tracks: Vec<LovedTrack>) -> anyhow::Result<()> {
+        self.add("loved_tracks", &loved_tracks)
+    }
+
+    fn write_friends(&mut self, friends: Vec<Friend>) -> anyhow::Result<()> {
+        self.add("friends", &friends)
+    }
+
+    fn write_friend_graph(&mut self, graph: FriendGraph) -> anyhow::Result<()> {
+        self.add("users", &graph.users)?;
+        self.add("friendships", &graph.friendships)
+    }
+
+    fn write_scrobbles(&mut self, scrobbles: Vec<Track>) -> anyhow::Result<()> {
+        self.add("scrobbles", &scrobbles)
+    }
+
+    fn write_now_playing(&mut self, now_playing: Vec<Track>) -> anyhow::Result<()> {
+        self.add("now_playing", &now_playing)
+    }
+
+    fn write_artist_tags(&mut self, artist_tags: Vec<ArtistTags>) -> anyhow::Result<()> {
+        self.add("artist_tags", &artist_tags)
+    }
+
+    fn finish(&mut self, success: bool) -> anyhow::Result<()> {
+        // Datasets that failed to fetch before anything arrived still get
+        // listed, so the manifest shows they're missing
+        for dataset in &self.incomplete {
+            self.datasets.entry(dataset.clone()).or_insert(DatasetInfo {
+                file: None,
+                count: 0,
+                complete: false,
+                sha256: None,
+            });
+        }
+
+        let finished = Utc::now();
+        let manifest = Manifest {
+            format_version: FORMAT_VERSION,
+            username: self.username.clone(),
+            started: self.started,
+            finished,
+            hatchery_version: env!("CARGO_PKG_VERSION").to_string(),
+            complete: success && self.incomplete.is_empty(),
+            datasets: std::mem::take(&mut self.datasets),
+        };
+
+        let mut builder = tar::Builder::new(Output::create(self.filename.clone(), &self.encoding)?);
+        append(
+            &mut builder,
+            MANIFEST,
+            &serde_json::to_vec_pretty(&manifest)?,
+            &finished,
+        )?;
+        for (name, data) in self.files.drain(..) {
+            append(&mut builder, &name, &data, &finished)?;
+        }
+        builder.into_inner()?.finish()?;
+        log::info!("Finished writing {}.", self.filename);
+
+        // Counts are in the archive's own manifest
+        self.written = Some(WrittenFile {
+            path: (self.filename.clone() + &self.encoding.extension()).into(),
+            format: FileFormat::Archive,
+            dataset: None,
+            count: None,
+            mutable: false,
+        });
+        Ok(())
+    }
+
+    fn written_files(&self) -> Vec<WrittenFile> {
+        self.written.iter().cloned().collect()
+    }
+}
+
+/// Whether `filename` is a tar archive once decrypted and decompressed,
+/// going by the magic bytes every tar header has 257 bytes in. Fails if it
+/// can't be decrypted.
+pub fn is_archive<P: AsRef<Path>>(filename: P, decryption: &Decryption) -> anyhow::Result<bool> {
+    let mut header = [0; 262];
+    let mut file = serialize::open(filename.as_ref(), decryption)?;
+    Ok(file.read_exact(&mut header).is_ok() && &header[257..] == b"ustar")
+}
+
+fn parse<T: DeserializeOwned>(
+    files: &HashMap<String, Vec<u8>>,
+    manifest: &Manifest,
+    dataset: &str,
+) -> anyhow::Result<Vec<T>> {
+    let (info, file) = match manifest.datasets.get(dataset) {
+        Some(
+            info @ DatasetInfo {
+                file: Some(file), ..
+            },
+        ) => (info, file),
+        _ => return Ok(Vec::new()),
+    };
+    let data = files
+        .get(file)
+        .ok_or_else(|| anyhow!("{} is listed in the manifest but missing", file))?;
+    if let Some(sha256) = &info.sha256 {
+        if format!("{:x}", Sha256::digest(data)) != *sha256 {
+            return Err(anyhow!("{} doesn't match its checksum", file));
+        }
+    }
+
+    let items: Vec<T> =
+        serde_json::from_slice(data).map_err(|e| anyhow!("Failed to read {}: {}", file, e))?;
+    if items.len() != info.count {
+        return Err(anyhow!(
+            "{} holds {} items, but the manifest says {}",
+            file,
+            items.len(),
+            info.count
+        ));
+    }
+    Ok(items)
+}
+
+/// Reads an archive written by `ArchiveSink`, however it's encoded, back into
+/// its manifest and the backup it holds.
+pub fn read_archive<P: AsRef<Path>>(
+    filename: P,
+    decryption: &Decryption,
+) -> anyhow::Result<(Manifest, Backup)> {
+    let mut archive = tar::Archive::new(serialize::open(filename.as_ref(), decryption)?);
+    let mut files: HashMap<String, Vec<u8>> = HashMap::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        files.insert(name, data);
+    }
+
+    let manifest: Manifest = match files.get(MANIFEST) {
+        Some(data) => serde_json::from_slice(data)
+            .map_err(|e| anyhow!("Failed to read {}: {}", MANIFEST, e))?,
+        None => return Err(anyhow!("Not a hatchery archive: no {}", MANIFEST)),
+    };
+    if manifest.format_version > FORMAT_VERSION {
+        return Err(anyhow!(
+            "Archive was written by hatchery {}, which is newer than this version",
+            manifest.hatchery_version
+        ));
+    }
+
+    let backup = Backup {
+        username: manifest.username.clone(),
+        loved_tracks: parse(&files, &manifest, "loved_tracks")?,
+        friends: parse(&files, &manifest, "friends")?,
+        friend_graph: FriendGraph {
+            users: parse(&files, &manifest, "users")?,
+            friendships: parse(&files, &manifest, "friendships")?,
+        },
+        scrobbles: parse(&files, &manifest, "scrobbles")?,
+        now_playing: parse(&files, &manifest, "now_playing")?,
+        artist_tags: parse(&files, &manifest, "artist_tags")?,
+        incomplete: manifest
+            .datasets
+            .iter()
+            .filter(|(_, info)| !info.complete)
+            .map(|(dataset, _)| dataset.clone())
+            .collect(),
+    };
+    Ok((manifest, backup))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialize::Compression;
+
+    fn scrobble(name: &str, timestamp: i64) -> Track {
+        serde_json::from_value(serde_json::json!({
+            "artist": {"name": "Artist"},
+            "name": name,
+            "date": {"pretty_string": "", "timestamp": timestamp},
+            "url": ""
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn archives_read_back_as_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let names = FileNames::new(dir.path().join("hatchery").to_string_lossy());
+        let encoding = Encoding {
+            compression: Some(Compression::Gzip),
+            encryption: None,
+        };
+        let mut sink = ArchiveSink::new(&names, false, encoding);
+        sink.begin_run("someone").unwrap();
+        sink.mark_incomplete("loved_tracks");
+        sink.write_scrobbles(vec![scrobble("A", 1), scrobble("B", 2)])
+            .unwrap();
+        sink.write_artist_tags(vec![ArtistTags {
+            artist: "Artist".to_string(),
+            genre: None,
+            tags: Vec::new(),
+        }])
+        .unwrap();
+        sink.finish(true).unwrap();
+
+        let filename = dir.path().join("hatchery.tar.gz");
+        assert_eq!(sink.written_files()[0].path, filename);
+        assert!(is_archive(&filename, &Decryption::default()).unwrap());
+        let (manifest, backup) = read_archive(&filename, &Decryption::default()).unwrap();
+        assert!(!manifest.complete);
+        assert_eq!(manifest.datasets["scrobbles"].count, 2);
+        assert_eq!(manifest.datasets["loved_tracks"].file, None);
+        assert_eq!(backup.username, "someone");
+        assert_eq!(backup.scrobbles, vec![scrobble("A", 1), scrobble("B", 2)]);
+        assert_eq!(backup.artist_tags.len(), 1);
+        assert!(backup.loved_tracks.is_empty());
+        assert_eq!(
+            backup.incomplete.into_iter().collect::<Vec<_>>(),
+            ["loved_tracks"]
+        );
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use super::encryption::Decryption;
+use super::serialize::{self, Encoding, Output};
+
+/// Bumped whenever the layout of checkpoints changes in a way older versions
+/// of hatchery can't read.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// The file in a checkpoint naming whose run it belongs to. Left plain, so
+/// that it can be read without any keys.
+const HEADER: &str = "checkpoint.json";
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Header {
+    format_version: u32,
+    username: String,
+    /// When the run started, which is also the `to` its scrobbles were
+    /// fetched with
+    started: DateTime<Utc>,
+}
+
+/// How far a dataset got before the run stopped.
+#[derive(Debug, Default)]
+pub struct DatasetProgress {
+    /// The last page written to the checkpoint
+    pub last_page: usize,
+    /// Whether every page was fetched
+    pub done: bool,
+    items: Vec<serde_json::Value>,
+}
+
+impl DatasetProgress {
+    /// Every item fetched so far, in the order they arrived.
+    pub fn items<T: DeserializeOwned>(&self) -> anyhow::Result<Vec<T>> {
+        self.items
+            .iter()
+            .map(|item| T::deserialize(item).map_err(|e| anyhow!("Bad checkpoint item: {}", e)))
+            .collect()
+    }
+}
+
+/// What an interrupted run left behind.
+#[derive(Debug)]
+pub struct Progress {
+    /// When the interrupted run started, to be used as `to` again
+    pub started: DateTime<Utc>,
+    datasets: HashMap<String, DatasetProgress>,
+}
+
+impl Progress {
+    pub fn dataset(&self, dataset: &str) -> Option<&DatasetProgress> {
+        self.datasets.get(dataset)
+    }
+}
+
+/// Records fetched pages as a run goes, so that an interrupted run can be
+/// resumed. A checkpoint is a directory holding every page as its own NDJSON
+/// file, compressed and encrypted like the rest of the run's files. Pages
+/// are written whole or not at all, so a crash can only lose the last one.
+pub struct Checkpoint {
+    path: PathBuf,
+    encoding: Encoding,
+}
+
+/// Where the checkpoint of `username`'s runs lives. Runs are named after
+/// when they start, so the name leaves that out for the next run to find it.
+pub fn checkpoint_path(directory: &Path, username: &str) -> PathBuf {
+    directory.join(format!(".hatchery-{}.checkpoint", username))
+}
+
+/// The dataset and page number of a page file, or just the dataset of a
+/// file marking that dataset as done.
+fn parse_page_filename(filename: &str) -> Option<(&str, Option<usize>)> {
+    if let Some(dataset) = filename.strip_suffix(".done") {
+        return Some((dataset, None));
+    }
+    let (dataset, page) = filename.strip_suffix(".ndjson")?.rsplit_once('-')?;
+    Some((dataset, Some(page.parse().ok()?)))
+}
+
+impl Checkpoint {
+    /// Starts a new checkpoint at `path`, replacing any that was there.
+    /// Pages are written with `encoding`.
+    pub fn create(
+        path: PathBuf,
+        username: &str,
+        started: DateTime<Utc>,
+        encoding: &Encoding,
+    ) -> anyhow::Result<Self> {
+        // Older versions wrote the checkpoint as a single file
+        if path.is_dir() {
+            fs::remove_dir_all(&path)?;
+        } else if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        fs::create_dir_all(&path)?;
+
+        let header = Header {
+            format_version: FORMAT_VERSION,
+            username: username.to_string(),
+            started,
+        };
+        serialize::write_json(
+            path.join(HEADER).to_string_lossy().into_owned(),
+            &header,
+            false,
+            &Encoding::default(),
+        )?;
+        Ok(Checkpoint {
+            path,
+            encoding: encoding.clone(),
+        })
+    }
+
+    /// Opens the checkpoint at `path` to carry on writing it with
+    /// `encoding`, returning how far it got. Pages are read back with
+    /// `decryption`, so resuming an encrypted run takes its key.
+    pub fn resume(
+        path: PathBuf,
+        username: &str,
+        encoding: &Encoding,
+        decryption: &Decryption,
+    ) -> anyhow::Result<(Self, Progress)> {
+        let header: Header = File::open(path.join(HEADER))
+            .map_err(anyhow::Error::from)
+            .and_then(|file| Ok(serde_json::from_reader(BufReader::new(file))?))
+            .map_err(|e| anyhow!("Not a checkpoint: {}", e))?;
+        if header.format_version > FORMAT_VERSION {
+            return Err(anyhow!(
+                "The checkpoint was written by a newer version of hatchery"
+            ));
+        }
+        if header.username != username {
+            return Err(anyhow!(
+                "The checkpoint is of {}, not {}",
+                header.username,
+                username
+            ));
+        }
+
+        let mut pages: HashMap<String, BTreeMap<usize, PathBuf>> = HashMap::new();
+        let mut done = HashSet::new();
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?;
+            let plain = serialize::strip_encoding(Path::new(&entry.file_name()));
+            match parse_page_filename(&plain.to_string_lossy()) {
+                Some((dataset, Some(page))) => {
+                    pages
+                        .entry(dataset.to_string())
+                        .or_default()
+                        .insert(page, entry.path());
+                }
+                Some((dataset, None)) => {
+                    done.insert(dataset.to_string());
+                }
+                None => {}
+            }
+        }
+
+        let mut datasets: HashMap<String, DatasetProgress> = HashMap::new();
+        for (dataset, pages) in pages {
+            let progress = datasets.entry(dataset.clone()).or_default();
+            // Pages are written in order, so a gap means the rest belong to
+            // no run that finished them
+            for (page, filename) in pages {
+                if page != progress.last_page + 1 {
+                    break;
+                }
+                for line in BufReader::new(serialize::open(&filename, decryption)?).lines() {
+                    progress.items.push(serde_json::from_str(&line?)?);
+                }
+                progress.last_page = page;
+            }
+        }
+        for dataset in done {
+            datasets.entry(dataset).or_default().done = true;
+        }
+
+        let progress = Progress {
+            started: header.started,
+            datasets,
+        };
+        let checkpoint = Checkpoint {
+            path,
+            encoding: encoding.clone(),
+        };
+        Ok((checkpoint, progress))
+    }
+
+    /// Records that `page` of `dataset` was fetched and handed on.
+    pub fn record_page<T: Serialize>(
+        &mut self,
+        dataset: &str,
+        page: usize,
+        items: &[T],
+    ) -> anyhow::Result<()> {
+        let filename = self.path.join(format!("{}-{:06}.ndjson", dataset, page));
+        let mut output = Output::create(filename.to_string_lossy().into_owned(), &self.encoding)?;
+        for item in items {
+            serde_json::to_writer(&mut output, item)?;
+            output.write_all(b"\n")?;
+        }
+        output.finish()
+    }
+
+    /// Records that every page of `dataset` was fetched.
+    pub fn record_done(&mut self, dataset: &str) -> anyhow::Result<()> {
+        File::create(self.path.join(format!("{}.done", dataset)))?;
+        Ok(())
+    }
+
+    /// Deletes the checkpoint once the run it belongs to has finished.
+    pub fn remove(self) -> anyhow::Result<()> {
+        fs::remove_dir_all(&self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::Encryption;
+    use crate::serialize::Compression;
+
+    #[test]
+    fn pages_are_encoded_and_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = checkpoint_path(dir.path(), "someone");
+        let encoding = Encoding {
+            compression: Some(Compression::Gzip),
+            encryption: Some(Encryption::Passphrase("secret".to_string())),
+        };
+        let started = Utc::now();
+
+        let mut checkpoint =
+            Checkpoint::create(path.clone(), "someone", started, &encoding).unwrap();
+        checkpoint
+            .record_page("scrobbles", 1, &["one", "two"])
+            .unwrap();
+        checkpoint.record_page("scrobbles", 2, &["three"]).unwrap();
+        assert!(path.join("scrobbles-000001.ndjson.gz.age").exists());
+
+        let decryption = Decryption::new(&[], Some("secret".to_string())).unwrap();
+        let (_, progress) =
+            Checkpoint::resume(path.clone(), "someone", &encoding, &decryption).unwrap();
+        let scrobbles = progress.dataset("scrobbles").unwrap();
+        assert_eq!(progress.started, started);
+        assert_eq!(scrobbles.last_page, 2);
+        assert!(!scrobbles.done);
+        assert_eq!(
+            scrobbles.items::<String>().unwrap(),
+            vec!["one", "two", "three"]
+        );
+
+        assert!(Checkpoint::resume(path, "someone", &encoding, &Decryption::default()).is_err());
+    }
+}
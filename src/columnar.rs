@@ -0,0 +1,482 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use arrow_array::{
+    ArrayRef, BooleanArray, RecordBatch, StringArray, TimestampSecondArray, UInt32Array,
+    UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use chrono::Datelike;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+
+use super::api::*;
+use super::graph::{FriendGraph, Friendship, User};
+use super::naming::FileNames;
+use super::serialize::{AtomicDir, AtomicFile};
+use super::sink::{BackupSink, FileFormat, WrittenFile};
+use super::tags::ArtistTags;
+
+fn timestamp_type() -> DataType {
+    DataType::Timestamp(TimeUnit::Second, Some("UTC".into()))
+}
+
+fn strings<'a>(values: impl Iterator<Item = &'a str>) -> ArrayRef {
+    Arc::new(StringArray::from_iter_values(values))
+}
+
+fn nullable_strings<'a>(values: impl Iterator<Item = Option<&'a str>>) -> ArrayRef {
+    Arc::new(values.collect::<StringArray>())
+}
+
+fn timestamps(values: impl Iterator<Item = Option<i64>>) -> ArrayRef {
+    Arc::new(
+        values
+            .collect::<TimestampSecondArray>()
+            .with_timezone("UTC"),
+    )
+}
+
+fn track_batch(tracks: &[Track]) -> anyhow::Result<RecordBatch> {
+    let schema = Schema::new(vec![
+        Field::new("timestamp", timestamp_type(), true),
+        Field::new("date", DataType::Utf8, true),
+        Field::new("name", DataType::Utf8, false),
+        Field::new("mbid", DataType::Utf8, true),
+        Field::new("url", DataType::Utf8, false),
+        Field::new("artist", DataType::Utf8, false),
+        Field::new("artist_mbid", DataType::Utf8, true),
+        Field::new("album", DataType::Utf8, true),
+        Field::new("album_mbid", DataType::Utf8, true),
+    ]);
+    Ok(RecordBatch::try_new(
+        Arc::new(schema),
+        vec![
+            timestamps(
+                tracks
+                    .iter()
+                    .map(|track| track.date.as_ref().map(|date| date.datetime.timestamp())),
+            ),
+            nullable_strings(
+                tracks
+                    .iter()
+                    .map(|track| track.date.as_ref().map(|date| date.pretty_string.as_str())),
+            ),
+            strings(tracks.iter().map(|track| track.name.as_str())),
+            nullable_strings(tracks.iter().map(|track| track.mbid.as_deref())),
+            strings(tracks.iter().map(|track| track.url.as_str())),
+            strings(tracks.iter().map(|track| track.artist.name.as_str())),
+            nullable_strings(tracks.iter().map(|track| track.artist.mbid.as_deref())),
+            nullable_strings(
+                tracks
+                    .iter()
+                    .map(|track| track.album.as_ref().map(|album| album.name.as_str())),
+            ),
+            nullable_strings(
+                tracks
+                    .iter()
+                    .map(|track| track.album.as_ref().and_then(|album| album.mbid.as_deref())),
+            ),
+        ],
+    )?)
+}
+
+fn loved_track_batch(tracks: &[LovedTrack]) -> anyhow::Result<RecordBatch> {
+    let schema = Schema::new(vec![
+        Field::new("timestamp", timestamp_type(), true),
+        Field::new("date", DataType::Utf8, true),
+        Field::new("name", DataType::Utf8, false),
+        Field::new("mbid", DataType::Utf8, true),
+        Field::new("url", DataType::Utf8, false),
+        Field::new("artist", DataType::Utf8, false),
+        Field::new("artist_mbid", DataType::Utf8, true),
+        Field::new("artist_url", DataType::Utf8, true),
+    ]);
+    Ok(RecordBatch::try_new(
+        Arc::new(schema),
+        vec![
+            timestamps(
+                tracks
+                    .iter()
+                    .map(|track| track.date.as_ref().map(|date| date.datetime.timestamp())),
+            ),
+            nullable_strings(
+                tracks
+                    .iter()
+                    .map(|track| track.date.as_ref().map(|date| date.pretty_string.as_str())),
+            ),
+            strings(tracks.iter().map(|track| track.name.as_str())),
+            nullable_strings(tracks.iter().map(|track| track.mbid.as_deref())),
+            strings(tracks.iter().map(|track| track.url.as_str())),
+            strings(tracks.iter().map(|track| track.artist.name.as_str())),
+            nullable_strings(tracks.iter().map(|track| track.artist.mbid.as_deref())),
+            nullable_strings(tracks.iter().map(|track| track.artist.url.as_deref())),
+        ],
+    )?)
+}
+
+fn friend_batch(friends: &[Friend]) -> anyhow::Result<RecordBatch> {
+    let schema = Schema::new(vec![
+        Field::new("name", DataType::Utf8, false),
+        Field::new("real_name", DataType::Utf8, true),
+        Field::new("country", DataType::Utf8, true),
+        Field::new("subscriber", DataType::Boolean, false),
+        Field::new("registered", timestamp_type(), false),
+        Field::new("url", DataType::Utf8, false),
+    ]);
+    Ok(RecordBatch::try_new(
+        Arc::new(schema),
+        vec![
+            strings(friends.iter().map(|friend| friend.name.as_str())),
+            nullable_strings(friends.iter().map(|friend| friend.real_name.as_deref())),
+            nullable_strings(friends.iter().map(|friend| friend.country.as_deref())),
+            Arc::new(
+                friends
+                    .iter()
+                    .map(|friend| Some(friend.subscriber))
+                    .collect::<BooleanArray>(),
+            ),
+            timestamps(
+                friends
+                    .iter()
+                    .map(|friend| Some(friend.registered.datetime.timestamp())),
+            ),
+            strings(friends.iter().map(|friend| friend.url.as_str())),
+        ],
+    )?)
+}
+
+fn user_batch(users: &[User]) -> anyhow::Result<RecordBatch> {
+    // Profile columns are null for the backed up account itself
+    let schema = Schema::new(vec![
+        Field::new("name", DataType::Utf8, false),
+        Field::new("depth", DataType::UInt64, false),
+        Field::new("real_name", DataType::Utf8, true),
+        Field::new("country", DataType::Utf8, true),
+        Field::new("subscriber", DataType::Boolean, true),
+        Field::new("registered", timestamp_type(), true),
+        Field::new("url", DataType::Utf8, true),
+    ]);
+    let profiles: Vec<Option<&Friend>> = users.iter().map(|user| user.profile.as_ref()).collect();
+    Ok(RecordBatch::try_new(
+        Arc::new(schema),
+        vec![
+            strings(users.iter().map(|user| user.name.as_str())),
+            Arc::new(UInt64Array::from_iter_values(
+                users.iter().map(|user| user.depth as u64),
+            )),
+            nullable_strings(
+                profiles
+                    .iter()
+                    .map(|profile| profile.and_then(|friend| friend.real_name.as_deref())),
+            ),
+            nullable_strings(
+                profiles
+                    .iter()
+                    .map(|profile| profile.and_then(|friend| friend.country.as_deref())),
+            ),
+            Arc::new(
+                profiles
+                    .iter()
+                    .map(|profile| profile.map(|friend| friend.subscriber))
+                    .collect::<BooleanArray>(),
+            ),
+            timestamps(
+                profiles
+                    .iter()
+                    .map(|profile| profile.map(|friend| friend.registered.datetime.timestamp())),
+            ),
+            nullable_strings(
+                profiles
+                    .iter()
+                    .map(|profile| profile.map(|friend| friend.url.as_str())),
+            ),
+        ],
+    )?)
+}
+
+fn friendship_batch(friendships: &[Friendship]) -> anyhow::Result<RecordBatch> {
+    let schema = Schema::new(vec![
+        Field::new("user", DataType::Utf8, false),
+        Field::new("friend", DataType::Utf8, false),
+    ]);
+    Ok(RecordBatch::try_new(
+        Arc::new(schema),
+        vec![
+            strings(
+                friendships
+                    .iter()
+                    .map(|friendship| friendship.user.as_str()),
+            ),
+            strings(
+                friendships
+                    .iter()
+                    .map(|friendship| friendship.friend.as_str()),
+            ),
+        ],
+    )?)
+}
+
+fn artist_tag_batch(artist_tags: &[ArtistTags]) -> anyhow::Result<RecordBatch> {
+    // One row per tag, or one without a tag for untagged artists, as in the
+    // CSV export
+    let rows: Vec<(&ArtistTags, Option<&Tag>)> = artist_tags
+        .iter()
+        .flat_map(|artist| {
+            let untagged = artist.tags.is_empty().then_some(None);
+            artist
+                .tags
+                .iter()
+                .map(Some)
+                .chain(untagged)
+                .map(move |tag| (artist, tag))
+        })
+        .collect();
+    let schema = Schema::new(vec![
+        Field::new("artist", DataType::Utf8, false),
+        Field::new("genre", DataType::Utf8, true),
+        Field::new("tag", DataType::Utf8, true),
+        Field::new("weight", DataType::UInt32, true),
+    ]);
+    Ok(RecordBatch::try_new(
+        Arc::new(schema),
+        vec![
+            strings(rows.iter().map(|(artist, _)| artist.artist.as_str())),
+            nullable_strings(rows.iter().map(|(artist, _)| artist.genre.as_deref())),
+            nullable_strings(rows.iter().map(|(_, tag)| tag.map(|tag| tag.name.as_str()))),
+            Arc::new(
+                rows.iter()
+                    .map(|(_, tag)| tag.map(|tag| tag.weight))
+                    .collect::<UInt32Array>(),
+            ),
+        ],
+    )?)
+}
+
+pub fn write_parquet<P: AsRef<Path>>(filename: P, batch: RecordBatch) -> anyhow::Result<()> {
+    let mut file = AtomicFile::create(filename)?;
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer = ArrowWriter::try_new(&mut file, batch.schema(), Some(properties))?;
+    writer.write(&batch)?;
+    writer.close()?;
+    file.persist()?;
+    Ok(())
+}
+
+/// Reads every row of a Parquet file back, returning how many there are.
+pub fn count_rows<P: AsRef<Path>>(filename: P) -> anyhow::Result<usize> {
+    let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(filename)?)?.build()?;
+    let mut count = 0;
+    for batch in reader {
+        count += batch?.num_rows();
+    }
+    Ok(count)
+}
+
+/// Writes each dataset to its own `{basename}-{dataset}.parquet` file with
+/// typed columns.
+///
+/// Scrobbles can instead be split into one file per year, laid out as
+/// `{basename}-scrobbles/year={year}/part-0.parquet` so that pandas, Polars
+/// and DuckDB pick the year up as a column when reading the directory.
+pub struct ParquetSink {
+    names: FileNames,
+    partition_by_year: bool,
+    written: Vec<WrittenFile>,
+}
+
+impl ParquetSink {
+    pub fn new(names: FileNames, partition_by_year: bool) -> Self {
+        ParquetSink {
+            names,
+            partition_by_year,
+            written: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, dataset: &str, batch: RecordBatch) -> anyhow::Result<()> {
+        self.write_file(self.names.dataset(dataset) + ".parquet", dataset, batch)
+    }
+
+    fn write_file(
+        &mut self,
+        filename: String,
+        dataset: &str,
+        batch: RecordBatch,
+    ) -> anyhow::Result<()> {
+        let count = batch.num_rows();
+        write_parquet(&filename, batch)?;
+        self.written.push(WrittenFile::dataset(
+            filename,
+            FileFormat::Parquet,
+            dataset,
+            count,
+        ));
+        Ok(())
+    }
+}
+
+impl BackupSink for ParquetSink {
+    fn begin_run(&mut self, _username: &str) -> anyhow::Result<()> {
+        log::info!("Writing Parquet...");
+        Ok(())
+    }
+
+    fn write_loved_tracks(&mut self, loved_tracks: Vec<LovedTrack>) -> anyhow::Result<()> {
+        self.write("loved_tracks", loved_track_batch(&loved_tracks)?)
+    }
+
+    fn write_friends(&mut self, friends: Vec<Friend>) -> anyhow::Result<()> {
+        self.write("friends", friend_batch(&friends)?)
+    }
+
+    fn write_friend_graph(&mut self, graph: FriendGraph) -> anyhow::Result<()> {
+        self.write("users", user_batch(&graph.users)?)?;
+        self.write("friendships", friendship_batch(&graph.friendships)?)
+    }
+
+    fn write_scrobbles(&mut self, scrobbles: Vec<Track>) -> anyhow::Result<()> {
+        if !self.partition_by_year {
+            return self.write("scrobbles", track_batch(&scrobbles)?);
+        }
+
+        let mut years: BTreeMap<Option<i32>, Vec<Track>> = BTreeMap::new();
+        for track in scrobbles {
+            let year = track.date.as_ref().map(|date| date.datetime.year());
+            years.entry(year).or_default().push(track);
+        }
+        // Partitions are written to a directory of their own that replaces
+        // the old one, so years an overwritten run had that this one doesn't
+        // are gone
+        let dataset = self.names.dataset("scrobbles");
+        let staged = AtomicDir::create(&dataset)?;
+        let mut written = Vec::new();
+        for (year, tracks) in years {
+            // Hive's name for a partition without a value
+            let year = match year {
+                Some(year) => year.to_string(),
+                None => "__HIVE_DEFAULT_PARTITION__".to_string(),
+            };
+            let partition = format!("year={}/part-0.parquet", year);
+            let batch = track_batch(&tracks)?;
+            let count = batch.num_rows();
+            write_parquet(staged.path().join(&partition), batch)?;
+            written.push(WrittenFile::dataset(
+                format!("{}/{}", dataset, partition),
+                FileFormat::Parquet,
+                "scrobbles",
+                count,
+            ));
+        }
+        staged.persist()?;
+        self.written.extend(written);
+        Ok(())
+    }
+
+    fn write_now_playing(&mut self, now_playing: Vec<Track>) -> anyhow::Result<()> {
+        self.write("now_playing", track_batch(&now_playing)?)
+    }
+
+    fn write_artist_tags(&mut self, artist_tags: Vec<ArtistTags>) -> anyhow::Result<()> {
+        self.write("artist_tags", artist_tag_batch(&artist_tags)?)
+    }
+
+    fn finish(&mut self, _success: bool) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn written_files(&self) -> Vec<WrittenFile> {
+        self.written.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn scrobble(year: i32) -> Track {
+        let timestamp = chrono::NaiveDate::from_ymd_opt(year, 6, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+            .and_utc()
+            .timestamp();
+        serde_json::from_value(serde_json::json!({
+            "artist": {"name": "Artist"},
+            "name": "Track",
+            "date": {"pretty_string": "", "timestamp": timestamp},
+            "url": ""
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn overwriting_partitions_drops_old_years() {
+        let dir = tempfile::tempdir().unwrap();
+        let names = FileNames::new(dir.path().join("hatchery").to_string_lossy());
+        let partitions = dir.path().join("hatchery-scrobbles");
+
+        let mut sink = ParquetSink::new(names.clone(), true);
+        sink.write_scrobbles(vec![scrobble(2019), scrobble(2020)])
+            .unwrap();
+        assert!(partitions.join("year=2019/part-0.parquet").exists());
+
+        let mut sink = ParquetSink::new(names, true);
+        sink.write_scrobbles(vec![scrobble(2020), scrobble(2021)])
+            .unwrap();
+        let mut years: Vec<String> = fs::read_dir(&partitions)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        years.sort();
+        assert_eq!(years, vec!["year=2020", "year=2021"]);
+        assert_eq!(
+            sink.written_files()
+                .iter()
+                .map(|file| count_rows(&file.path).unwrap())
+                .sum::<usize>(),
+            2
+        );
+
+        // Nothing but the dataset is left next to it
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn artists_without_tags_still_get_a_row() {
+        let batch = artist_tag_batch(&[
+            ArtistTags {
+                artist: "Tagged".to_string(),
+                genre: None,
+                tags: vec![
+                    Tag {
+                        name: "rock".to_string(),
+                        weight: 100,
+                    },
+                    Tag {
+                        name: "pop".to_string(),
+                        weight: 50,
+                    },
+                ],
+            },
+            ArtistTags {
+                artist: "Untagged".to_string(),
+                genre: None,
+                tags: Vec::new(),
+            },
+        ])
+        .unwrap();
+
+        assert_eq!(batch.num_rows(), 3);
+        assert_eq!(batch.column(2).null_count(), 1);
+        assert_eq!(batch.column(3).null_count(), 1);
+    }
+}
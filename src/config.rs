@@ -0,0 +1,71 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dataset {
+    LovedTracks,
+    Friends,
+    Scrobbles,
+}
+
+impl Dataset {
+    pub fn all() -> Vec<Dataset> {
+        vec![Dataset::LovedTracks, Dataset::Friends, Dataset::Scrobbles]
+    }
+}
+
+/// An account listed in a config file. Anything left out falls back to the
+/// config file's top-level settings, and then to the command line.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountConfig {
+    pub username: String,
+    pub api_key: Option<String>,
+    pub api_secret: Option<String>,
+    pub datasets: Option<Vec<Dataset>>,
+    pub output_dir: Option<PathBuf>,
+    /// Database to sync into instead of writing a new backup every run
+    pub sync: Option<PathBuf>,
+    /// PostgreSQL database to sync into instead, which needs the `postgres`
+    /// feature
+    pub postgres: Option<String>,
+}
+
+/// A config file listing the accounts to back up, e.g.
+///
+/// ```toml
+/// api_key = "..."
+/// api_secret = "..."
+/// output_dir = "backups"
+///
+/// [[account]]
+/// username = "alice"
+///
+/// [[account]]
+/// username = "bob"
+/// datasets = ["scrobbles", "loved_tracks"]
+/// output_dir = "backups/bob"
+/// sync = "backups/bob.db"
+///
+/// [[account]]
+/// username = "carol"
+/// postgres = "postgresql://carol@localhost/hatchery"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub api_key: Option<String>,
+    pub api_secret: Option<String>,
+    pub datasets: Option<Vec<Dataset>>,
+    pub output_dir: Option<PathBuf>,
+    #[serde(rename = "account", default)]
+    pub accounts: Vec<AccountConfig>,
+}
+
+pub fn read_config<P: AsRef<Path>>(filename: P) -> anyhow::Result<Config> {
+    let contents = fs::read_to_string(filename)?;
+    Ok(toml::from_str(&contents)?)
+}
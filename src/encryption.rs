@@ -0,0 +1,164 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::iter;
+use std::path::PathBuf;
+
+use age::secrecy::SecretString;
+use age::stream::{StreamReader, StreamWriter};
+use age::{x25519, Decryptor, Encryptor, Identity, IdentityFile};
+use anyhow::anyhow;
+
+/// Every age file starts with this.
+pub const MAGIC: &[u8] = b"age-encryption.org/v1";
+
+/// Appended to the name of every encrypted file.
+pub const EXTENSION: &str = ".age";
+
+/// Who can read encrypted backups.
+#[derive(Clone)]
+pub enum Encryption {
+    /// Whoever holds the identity of one of these public keys
+    Recipients(Vec<x25519::Recipient>),
+    /// Whoever knows the passphrase
+    Passphrase(String),
+}
+
+impl Encryption {
+    pub fn wrap<W: Write>(&self, output: W) -> anyhow::Result<StreamWriter<W>> {
+        let encryptor = match self {
+            Encryption::Recipients(recipients) => Encryptor::with_recipients(
+                recipients
+                    .iter()
+                    .map(|recipient| recipient as &dyn age::Recipient),
+            )?,
+            Encryption::Passphrase(passphrase) => {
+                Encryptor::with_user_passphrase(SecretString::from(passphrase.clone()))
+            }
+        };
+        Ok(encryptor.wrap_output(output)?)
+    }
+}
+
+// Keeps the passphrase out of logs
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Encryption::Recipients(recipients) => {
+                f.debug_tuple("Recipients").field(recipients).finish()
+            }
+            Encryption::Passphrase(_) => f.write_str("Passphrase"),
+        }
+    }
+}
+
+/// The keys encrypted backups can be read with. Files encrypted with a
+/// passphrase need the passphrase, and files encrypted to public keys need
+/// one of their identities.
+#[derive(Default)]
+pub struct Decryption {
+    identities: Vec<Box<dyn Identity>>,
+    passphrase: Option<String>,
+}
+
+impl Decryption {
+    /// Reads every identity in `identity_files`, which are in the format
+    /// `age-keygen` writes.
+    pub fn new(identity_files: &[PathBuf], passphrase: Option<String>) -> anyhow::Result<Self> {
+        let mut identities = Vec::new();
+        for filename in identity_files {
+            let file = IdentityFile::from_file(filename.to_string_lossy().into_owned())
+                .map_err(|e| anyhow!("Failed to read {}: {}", filename.display(), e))?;
+            identities.extend(file.into_identities()?);
+        }
+        Ok(Decryption {
+            identities,
+            passphrase,
+        })
+    }
+
+    pub fn wrap<R: Read>(&self, input: R) -> anyhow::Result<StreamReader<R>> {
+        let decryptor = Decryptor::new(input)?;
+        if decryptor.is_scrypt() {
+            let passphrase = self
+                .passphrase
+                .as_ref()
+                .ok_or_else(|| anyhow!("Encrypted with a passphrase, but none was given"))?;
+            let identity = age::scrypt::Identity::new(SecretString::from(passphrase.clone()));
+            Ok(decryptor.decrypt(iter::once(&identity as &dyn Identity))?)
+        } else if self.identities.is_empty() {
+            Err(anyhow!(
+                "Encrypted to a public key, but no identity file was given"
+            ))
+        } else {
+            Ok(decryptor.decrypt(self.identities.iter().map(|identity| identity.as_ref()))?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::secrecy::ExposeSecret;
+    use std::path::Path;
+
+    use crate::serialize::{self, Encoding, Output};
+
+    /// Writes a file encrypted with `encryption` to `dir`, returning its name.
+    fn encrypt(dir: &Path, encryption: Encryption) -> PathBuf {
+        let filename = dir.join("backup.json");
+        let encoding = Encoding {
+            compression: None,
+            encryption: Some(encryption),
+        };
+        let mut output =
+            Output::create(filename.to_string_lossy().into_owned(), &encoding).unwrap();
+        output.write_all(b"secret").unwrap();
+        output.finish().unwrap();
+
+        let filename = dir.join(format!("backup.json{}", EXTENSION));
+        assert!(std::fs::read(&filename).unwrap().starts_with(MAGIC));
+        filename
+    }
+
+    fn decrypt(filename: &Path, decryption: &Decryption) -> anyhow::Result<String> {
+        let mut contents = String::new();
+        serialize::open(filename, decryption)?.read_to_string(&mut contents)?;
+        Ok(contents)
+    }
+
+    #[test]
+    fn files_encrypted_with_a_passphrase_need_it_to_be_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = encrypt(
+            dir.path(),
+            Encryption::Passphrase("correct horse".to_string()),
+        );
+
+        let decryption = Decryption::new(&[], Some("correct horse".to_string())).unwrap();
+        assert_eq!(decrypt(&filename, &decryption).unwrap(), "secret");
+        let wrong = Decryption::new(&[], Some("battery staple".to_string())).unwrap();
+        assert!(decrypt(&filename, &wrong).is_err());
+        assert!(decrypt(&filename, &Decryption::default()).is_err());
+    }
+
+    #[test]
+    fn files_encrypted_to_a_recipient_need_its_identity_to_be_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = x25519::Identity::generate();
+        let filename = encrypt(
+            dir.path(),
+            Encryption::Recipients(vec![identity.to_public()]),
+        );
+
+        let identity_file = dir.path().join("key.txt");
+        std::fs::write(&identity_file, identity.to_string().expose_secret()).unwrap();
+        let decryption = Decryption::new(&[identity_file], None).unwrap();
+        assert_eq!(decrypt(&filename, &decryption).unwrap(), "secret");
+        let wrong = Decryption {
+            identities: vec![Box::new(x25519::Identity::generate())],
+            passphrase: None,
+        };
+        assert!(decrypt(&filename, &wrong).is_err());
+        assert!(decrypt(&filename, &Decryption::default()).is_err());
+    }
+}
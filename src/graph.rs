@@ -0,0 +1,214 @@
+use std::collections::{HashSet, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use super::api::*;
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct User {
+    pub name: String,
+    /// Number of friendships between this user and the backed up account
+    pub depth: usize,
+    /// Profile as reported in the friend list it was discovered in. The
+    /// backed up account itself has none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<Friend>,
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Friendship {
+    pub user: String,
+    pub friend: String,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FriendGraph {
+    pub users: Vec<User>,
+    pub friendships: Vec<Friendship>,
+}
+
+/// Walks the friends of `username` breadth-first, up to `max_depth`
+/// friendships away and `max_users` users in total.
+///
+/// `friends` are the account's own friends, which have usually been fetched
+/// already. Users whose friends can't be fetched (e.g. private profiles) are
+/// kept in the graph but not walked any further.
+pub fn crawl(
+    client: &mut LastFM,
+    username: &str,
+    friends: &[Friend],
+    max_depth: usize,
+    max_users: usize,
+) -> FriendGraph {
+    let mut graph = FriendGraph::default();
+    let mut seen_users: HashSet<String> = HashSet::new();
+    let mut seen_friendships: HashSet<(String, String)> = HashSet::new();
+    let mut queue: VecDeque<(String, usize)> = VecDeque::new();
+
+    graph.users.push(User {
+        name: username.to_string(),
+        depth: 0,
+        profile: None,
+    });
+    seen_users.insert(username.to_lowercase());
+    queue.push_back((username.to_string(), 0));
+
+    while let Some((name, depth)) = queue.pop_front() {
+        let fetched_friends = if depth == 0 {
+            friends.to_vec()
+        } else {
+            log::info!("Fetching friends of {} (depth {})...", name, depth);
+            match client.friends(&name) {
+                Ok(fetched_friends) => fetched_friends,
+                Err(_) => {
+                    log::warn!("Failed to fetch friends of {}. Skipping.", name);
+                    continue;
+                }
+            }
+        };
+
+        for friend in fetched_friends {
+            let key = friend.name.to_lowercase();
+            if !seen_users.contains(&key) {
+                if graph.users.len() >= max_users {
+                    // Only record edges between users already in the graph
+                    continue;
+                }
+                seen_users.insert(key.clone());
+                if depth + 1 < max_depth {
+                    queue.push_back((friend.name.clone(), depth + 1));
+                }
+                graph.users.push(User {
+                    name: friend.name.clone(),
+                    depth: depth + 1,
+                    profile: Some(friend.clone()),
+                });
+            }
+
+            // Friendships are mutual, so only store each pair once
+            let own_key = name.to_lowercase();
+            let pair = if own_key < key {
+                (own_key, key)
+            } else {
+                (key, own_key)
+            };
+            if seen_friendships.insert(pair) {
+                graph.friendships.push(Friendship {
+                    user: name.clone(),
+                    friend: friend.name,
+                });
+            }
+        }
+    }
+
+    if graph.users.len() >= max_users {
+        log::warn!(
+            "Reached the limit of {} users. Graph is partial.",
+            max_users
+        );
+    }
+    graph
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, HashMap};
+
+    use crate::raw::RawPage;
+
+    fn friend(name: &str) -> serde_json::Value {
+        serde_json::json!({
+            "name": name,
+            "url": format!("https://www.last.fm/user/{}", name),
+            "registered": {"#text": "", "unixtime": "1262304000"},
+        })
+    }
+
+    /// A client that answers `user.getFriends` for each user in `friends`
+    /// with the friends listed, and fails for anyone else.
+    fn stub(friends: &[(&str, &[&str])]) -> LastFM {
+        let mut pages = HashMap::new();
+        for (user, names) in friends {
+            let params: BTreeMap<String, String> = [("limit", "50"), ("page", "1"), ("user", user)]
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            let body = serde_json::json!({
+                "friends": {
+                    "@attr": {
+                        "page": "1",
+                        "perPage": "50",
+                        "total": names.len().to_string(),
+                        "totalPages": "1",
+                        "user": user,
+                    },
+                    "user": names.iter().map(|name| friend(name)).collect::<Vec<_>>(),
+                },
+            });
+            pages.insert(RawPage::key("user.getFriends", &params), body.to_string());
+        }
+        LastFM::replay(pages)
+    }
+
+    fn own_friends(names: &[&str]) -> Vec<Friend> {
+        names
+            .iter()
+            .map(|name| serde_json::from_value(friend(name)).unwrap())
+            .collect()
+    }
+
+    fn users(graph: &FriendGraph) -> Vec<(&str, usize)> {
+        graph
+            .users
+            .iter()
+            .map(|user| (user.name.as_str(), user.depth))
+            .collect()
+    }
+
+    fn friendships(graph: &FriendGraph) -> Vec<(&str, &str)> {
+        graph
+            .friendships
+            .iter()
+            .map(|friendship| (friendship.user.as_str(), friendship.friend.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn friends_are_only_walked_up_to_the_depth_limit() {
+        let friends: &[(&str, &[&str])] = &[("bob", &["carol"]), ("carol", &["dave"])];
+
+        let graph = crawl(&mut stub(friends), "alice", &own_friends(&["bob"]), 1, 100);
+        assert_eq!(users(&graph), [("alice", 0), ("bob", 1)]);
+
+        let graph = crawl(&mut stub(friends), "alice", &own_friends(&["bob"]), 2, 100);
+        assert_eq!(users(&graph), [("alice", 0), ("bob", 1), ("carol", 2)]);
+        assert_eq!(friendships(&graph), [("alice", "bob"), ("bob", "carol")]);
+    }
+
+    #[test]
+    fn cycles_are_walked_once_and_friendships_stored_once() {
+        // Friendships go both ways, and names differ in case between lists
+        let friends: &[(&str, &[&str])] =
+            &[("bob", &["Alice", "carol"]), ("carol", &["alice", "Bob"])];
+
+        let graph = crawl(
+            &mut stub(friends),
+            "alice",
+            &own_friends(&["bob", "carol"]),
+            5,
+            100,
+        );
+        assert_eq!(users(&graph), [("alice", 0), ("bob", 1), ("carol", 1)]);
+        assert_eq!(
+            friendships(&graph),
+            [("alice", "bob"), ("alice", "carol"), ("bob", "carol")]
+        );
+    }
+
+    #[test]
+    fn users_whose_friends_cant_be_fetched_are_kept() {
+        let graph = crawl(&mut stub(&[]), "alice", &own_friends(&["bob"]), 3, 100);
+        assert_eq!(users(&graph), [("alice", 0), ("bob", 1)]);
+    }
+}
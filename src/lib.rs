@@ -0,0 +1,20 @@
+//! Backs up Last.fm accounts. The `hatchery` binary is a thin wrapper around
+//! this library; implement [`sink::BackupSink`] to write backups elsewhere.
+
+pub mod api;
+pub mod archive;
+pub mod checkpoint;
+#[cfg(feature = "parquet")]
+pub mod columnar;
+pub mod encryption;
+pub mod graph;
+pub mod naming;
+#[cfg(feature = "postgres")]
+pub mod pg;
+pub mod raw;
+pub mod retention;
+pub mod serialize;
+pub mod sink;
+pub mod sql;
+pub mod tags;
+pub mod verify;
@@ -0,0 +1,208 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local};
+use clap::ArgEnum;
+
+/// Stands in for the dataset in filename templates.
+const DATASET: &str = "{dataset}";
+
+/// What to do when a file a run would write already exists.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Collision {
+    /// Write over it
+    Overwrite,
+    /// Add `-2`, `-3` and so on to the new files' names until none exist
+    Suffix,
+    /// Stop before fetching anything
+    Fail,
+}
+
+/// How a run names the files it writes, without their extensions.
+///
+/// Each dataset gets its own file, named after the template with `{dataset}`
+/// filled in. Templates without `{dataset}` get `-{dataset}` added. Files
+/// that hold every dataset, such as databases and archives, have `all` in
+/// place of `{dataset}`, or nothing added.
+#[derive(Clone, Debug)]
+pub struct FileNames {
+    stem: String,
+}
+
+impl FileNames {
+    /// Names files `{basename}-{dataset}`, or just `basename` for files
+    /// holding every dataset.
+    pub fn new<S: Into<String>>(basename: S) -> Self {
+        FileNames {
+            stem: basename.into(),
+        }
+    }
+
+    /// Fills in a template such as `{username}/hatchery-%Y-%m-%d-{dataset}`,
+    /// taking strftime fields from `time`, and puts it in `directory`.
+    pub fn from_template(
+        directory: &Path,
+        template: &str,
+        username: &str,
+        format: &str,
+        time: &DateTime<Local>,
+    ) -> anyhow::Result<Self> {
+        let items: Vec<Item> = StrftimeItems::new(template).collect();
+        if items.contains(&Item::Error) {
+            return Err(anyhow!("Invalid strftime field in {}", template));
+        }
+        // Filled in after the strftime fields, so that a % in a username
+        // isn't taken for one
+        let stem = time
+            .format_with_items(items.into_iter())
+            .to_string()
+            .replace("{username}", username)
+            .replace("{format}", format);
+        Ok(FileNames::new(directory.join(stem).to_string_lossy()))
+    }
+
+    /// Where `dataset` is written.
+    pub fn dataset(&self, dataset: &str) -> String {
+        if self.stem.contains(DATASET) {
+            self.stem.replace(DATASET, dataset)
+        } else {
+            format!("{}-{}", self.stem, dataset)
+        }
+    }
+
+    /// Where a file holding every dataset is written.
+    pub fn whole(&self) -> String {
+        self.stem.replace(DATASET, "all")
+    }
+
+    /// Where the run's manifest is written.
+    pub fn manifest(&self) -> String {
+        self.dataset("manifest") + ".json"
+    }
+
+    /// The same names with `-{suffix}` added, before `-{dataset}` if the
+    /// template didn't have its own.
+    pub fn with_suffix(&self, suffix: &str) -> Self {
+        FileNames::new(format!("{}-{}", self.stem, suffix))
+    }
+
+    /// Applies `collision` to these names. `outputs` lists every file a run
+    /// could write under a set of names.
+    pub fn avoid_collisions<F>(self, collision: Collision, outputs: F) -> anyhow::Result<Self>
+    where
+        F: Fn(&FileNames) -> Vec<PathBuf>,
+    {
+        let existing = |names: &FileNames| outputs(names).into_iter().find(|path| path.exists());
+        match collision {
+            Collision::Overwrite => Ok(self),
+            Collision::Fail => match existing(&self) {
+                Some(path) => Err(anyhow!("{} already exists", path.display())),
+                None => Ok(self),
+            },
+            Collision::Suffix => {
+                if existing(&self).is_none() {
+                    return Ok(self);
+                }
+                let names = (2..)
+                    .map(|n| self.with_suffix(&n.to_string()))
+                    .find(|names| existing(names).is_none())
+                    .expect("Some suffix is free");
+                Ok(names)
+            }
+        }
+    }
+}
+
+/// Creates the directory `filename` goes in, for templates that put files in
+/// directories of their own.
+pub fn create_parent_dir<P: AsRef<Path>>(filename: P) -> io::Result<()> {
+    match filename.as_ref().parent() {
+        Some(directory) if directory != Path::new("") => fs::create_dir_all(directory),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn names(template: &str) -> FileNames {
+        let time = Local.with_ymd_and_hms(2021, 6, 1, 12, 30, 0).unwrap();
+        FileNames::from_template(Path::new("out"), template, "some%one", "csv", &time).unwrap()
+    }
+
+    #[test]
+    fn templates_are_filled_in() {
+        let names = names("{username}/hatchery-%Y-%m-%d-{format}-{dataset}");
+        assert_eq!(
+            names.dataset("scrobbles"),
+            "out/some%one/hatchery-2021-06-01-csv-scrobbles"
+        );
+        assert_eq!(names.whole(), "out/some%one/hatchery-2021-06-01-csv-all");
+        assert_eq!(
+            names.manifest(),
+            "out/some%one/hatchery-2021-06-01-csv-manifest.json"
+        );
+    }
+
+    #[test]
+    fn templates_without_a_dataset_get_one_added() {
+        let names = names("hatchery-%H%M");
+        assert_eq!(names.dataset("scrobbles"), "out/hatchery-1230-scrobbles");
+        assert_eq!(names.whole(), "out/hatchery-1230");
+    }
+
+    #[test]
+    fn bad_strftime_fields_are_rejected() {
+        let time = Local::now();
+        assert!(FileNames::from_template(Path::new(""), "%Q", "someone", "csv", &time).is_err());
+    }
+
+    #[test]
+    fn suffixes_go_before_an_added_dataset() {
+        assert_eq!(
+            names("hatchery").with_suffix("2").dataset("scrobbles"),
+            "out/hatchery-2-scrobbles"
+        );
+        assert_eq!(
+            names("{dataset}-hatchery")
+                .with_suffix("2")
+                .dataset("scrobbles"),
+            "out/scrobbles-hatchery-2"
+        );
+    }
+
+    fn collide(directory: &Path, collision: Collision) -> anyhow::Result<FileNames> {
+        FileNames::new(directory.join("hatchery").to_string_lossy())
+            .avoid_collisions(collision, |names| {
+                vec![PathBuf::from(names.dataset("scrobbles"))]
+            })
+    }
+
+    #[test]
+    fn collisions_are_handled_by_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let taken = |name: &str| fs::write(dir.path().join(name), "").unwrap();
+        let first = dir.path().join("hatchery-scrobbles");
+        let third = dir.path().join("hatchery-3-scrobbles");
+
+        // Nothing exists yet, so every policy keeps the names
+        for collision in [Collision::Overwrite, Collision::Suffix, Collision::Fail] {
+            let names = collide(dir.path(), collision).unwrap();
+            assert_eq!(Path::new(&names.dataset("scrobbles")), first);
+        }
+
+        taken("hatchery-scrobbles");
+        taken("hatchery-2-scrobbles");
+        let names = collide(dir.path(), Collision::Overwrite).unwrap();
+        assert_eq!(Path::new(&names.dataset("scrobbles")), first);
+        let names = collide(dir.path(), Collision::Suffix).unwrap();
+        assert_eq!(Path::new(&names.dataset("scrobbles")), third);
+        assert!(collide(dir.path(), Collision::Fail).is_err());
+    }
+}
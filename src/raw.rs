@@ -0,0 +1,162 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::encryption::Decryption;
+use super::naming::FileNames;
+use super::serialize::{self, Encoding, Output};
+use super::sink::{FileFormat, WrittenFile};
+
+/// Bumped whenever the layout of raw page files changes in a way older
+/// versions of hatchery can't read.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The first line of a raw page file.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RawHeader {
+    pub format_version: u32,
+    pub username: String,
+    /// When the run started, which is also the `to` its scrobbles were
+    /// fetched with
+    pub started: DateTime<Utc>,
+    pub hatchery_version: String,
+    /// Set when the run resumed an interrupted one with `--resume`: the last
+    /// page of scrobbles that run had fetched, which aren't in this file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resumed_after: Option<usize>,
+}
+
+/// A response exactly as Last.fm sent it, errors included.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RawPage {
+    pub method: String,
+    /// The parameters it was requested with, leaving out credentials
+    pub params: BTreeMap<String, String>,
+    pub fetched: DateTime<Utc>,
+    pub body: String,
+}
+
+impl RawPage {
+    /// Identifies the request a page answers, so it can be looked up again.
+    pub fn key(method: &str, params: &BTreeMap<String, String>) -> String {
+        let mut key = method.to_string();
+        for (name, value) in params {
+            key.push_str(&format!("&{}={}", name, value));
+        }
+        key
+    }
+}
+
+/// Writes every page handed to it to a single NDJSON file, one page per line
+/// after a header.
+pub struct RawRecorder {
+    filename: String,
+    output: Output,
+    pages: usize,
+}
+
+/// Raw pages are mostly markup, so they're compressed with gzip even when no
+/// compression was asked for.
+fn raw_encoding(encoding: &Encoding) -> Encoding {
+    let mut encoding = encoding.clone();
+    encoding.compression = encoding.compression.or(Some(serialize::Compression::Gzip));
+    encoding
+}
+
+/// Where a run named by `names` saves its raw pages.
+pub fn raw_filename(names: &FileNames, encoding: &Encoding) -> String {
+    names.dataset("raw") + ".ndjson" + &raw_encoding(encoding).extension()
+}
+
+impl RawRecorder {
+    /// Creates the raw page file of a run named by `names`, which started at
+    /// `started`, or resumed a run that did after its page `resumed_after`
+    /// of scrobbles.
+    pub fn create(
+        names: &FileNames,
+        username: &str,
+        started: DateTime<Utc>,
+        resumed_after: Option<usize>,
+        encoding: &Encoding,
+    ) -> anyhow::Result<Self> {
+        let encoding = raw_encoding(encoding);
+        let mut output = Output::create(names.dataset("raw") + ".ndjson", &encoding)?;
+        let header = RawHeader {
+            format_version: FORMAT_VERSION,
+            username: username.to_string(),
+            started,
+            hatchery_version: env!("CARGO_PKG_VERSION").to_string(),
+            resumed_after,
+        };
+        serde_json::to_writer(&mut output, &header)?;
+        output.write_all(b"\n")?;
+        Ok(RawRecorder {
+            filename: raw_filename(names, &encoding),
+            output,
+            pages: 0,
+        })
+    }
+
+    pub fn record(&mut self, page: &RawPage) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut self.output, page)?;
+        self.output.write_all(b"\n")?;
+        self.pages += 1;
+        Ok(())
+    }
+
+    /// Finishes the file, returning it for the run's manifest.
+    pub fn finish(self) -> anyhow::Result<WrittenFile> {
+        self.output.finish()?;
+        Ok(WrittenFile {
+            path: PathBuf::from(self.filename),
+            format: FileFormat::Raw,
+            dataset: None,
+            count: Some(self.pages),
+            mutable: false,
+        })
+    }
+}
+
+/// Reads a file written by `RawRecorder`, however it's encoded.
+pub fn read_raw_pages(
+    filename: &Path,
+    decryption: &Decryption,
+) -> anyhow::Result<(RawHeader, Vec<RawPage>)> {
+    let mut lines = BufReader::new(serialize::open(filename, decryption)?).lines();
+    let header: RawHeader = match lines.next() {
+        Some(line) => {
+            serde_json::from_str(&line?).map_err(|e| anyhow!("Not a raw page file: {}", e))?
+        }
+        None => return Err(anyhow!("Not a raw page file: it's empty")),
+    };
+    if header.format_version > FORMAT_VERSION {
+        return Err(anyhow!(
+            "Raw pages were written by hatchery {}, which is newer than this version",
+            header.hatchery_version
+        ));
+    }
+
+    let mut pages = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        // Line numbers count the header
+        pages.push(serde_json::from_str(&line).map_err(|e| anyhow!("Line {}: {}", i + 2, e))?);
+    }
+    Ok((header, pages))
+}
+
+/// Raw pages looked up by the request they answer. Where a request was
+/// retried, the last response wins.
+pub fn index_pages(pages: Vec<RawPage>) -> HashMap<String, String> {
+    pages
+        .into_iter()
+        .map(|page| (RawPage::key(&page.method, &page.params), page.body))
+        .collect()
+}
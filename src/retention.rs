@@ -0,0 +1,369 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, Utc};
+
+use super::verify::read_manifest;
+
+/// Which backups to keep when pruning. Every rule only counts complete
+/// backups, and a backup is kept if any rule keeps it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Retention {
+    /// The most recent backups
+    pub last: usize,
+    /// The newest backup of each of the most recent days
+    pub daily: usize,
+    /// The newest backup of each of the most recent weeks
+    pub weekly: usize,
+    /// The newest backup of each of the most recent months
+    pub monthly: usize,
+}
+
+impl Retention {
+    /// Whether no rules were given, in which case nothing is pruned.
+    pub fn is_empty(&self) -> bool {
+        self.last == 0 && self.daily == 0 && self.weekly == 0 && self.monthly == 0
+    }
+}
+
+/// A backup found on disk, going by its run manifest.
+#[derive(Debug)]
+pub struct Run {
+    pub manifest: PathBuf,
+    pub username: String,
+    /// The account that backed this user up as its friend, for runs of
+    /// `--include-friends`
+    pub friend_of: Option<String>,
+    pub finished: DateTime<Utc>,
+    pub complete: bool,
+    pub files: Vec<PathBuf>,
+}
+
+/// Every backup in `directory` with a run manifest, and in the directories
+/// below it if `recursive`. Backups written before manifests were added
+/// aren't found, so they're never pruned.
+pub fn find_runs(directory: &Path, recursive: bool) -> anyhow::Result<Vec<Run>> {
+    let mut runs = Vec::new();
+    let mut directories = vec![directory.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        for entry in fs::read_dir(&directory)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                if recursive {
+                    directories.push(path);
+                }
+            } else if is_manifest(&path) {
+                runs.extend(read_run(&directory, path));
+            }
+        }
+    }
+    Ok(runs)
+}
+
+/// Whether `path` is named like a manifest, which is always a JSON file with
+/// `manifest` in its name.
+fn is_manifest(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| {
+        let name = name.to_string_lossy();
+        name.contains("manifest") && name.ends_with(".json")
+    })
+}
+
+fn read_run(directory: &Path, path: PathBuf) -> Option<Run> {
+    match read_manifest(&path) {
+        Ok(manifest) => Some(Run {
+            files: manifest
+                .files
+                .iter()
+                .map(|info| directory.join(&info.file))
+                .collect(),
+            manifest: path,
+            username: manifest.username,
+            friend_of: manifest.friend_of,
+            finished: manifest.finished,
+            complete: manifest.complete,
+        }),
+        Err(e) => {
+            log::warn!("Skipping {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// What pruning would do to every backup, newest first. Backups that are
+/// kept come with the reason why.
+#[derive(Debug)]
+pub struct Plan {
+    pub runs: Vec<(Run, Option<&'static str>)>,
+}
+
+impl Plan {
+    /// Works out which of `runs` to keep, judging each user's backups
+    /// separately, and those another account made of them as its friend
+    /// separately again. The newest complete backup of each is always kept,
+    /// as are incomplete backups newer than it, so a user with no complete
+    /// backup keeps everything.
+    pub fn new(runs: Vec<Run>, retention: &Retention, now: DateTime<Local>) -> Self {
+        let mut users: BTreeMap<(String, Option<String>), Vec<Run>> = BTreeMap::new();
+        for run in runs {
+            let key = (run.username.clone(), run.friend_of.clone());
+            users.entry(key).or_default().push(run);
+        }
+
+        let today = now.date_naive();
+        let mut planned = Vec::new();
+        for (_, mut runs) in users {
+            runs.sort_by_key(|run| Reverse(run.finished));
+            let mut seen_good = false;
+            let mut good = 0;
+            let mut days = HashSet::new();
+            let mut weeks = HashSet::new();
+            let mut months = HashSet::new();
+
+            for run in runs {
+                if !run.complete {
+                    let reason = (!seen_good).then_some("newer than every complete backup");
+                    planned.push((run, reason));
+                    continue;
+                }
+
+                let date = run.finished.with_timezone(&Local).date_naive();
+                let week = date.iso_week();
+                let month = (date.year(), date.month());
+                let mut reason = None;
+                if !seen_good {
+                    reason = Some("newest complete backup");
+                } else if good < retention.last {
+                    reason = Some("last");
+                }
+                if within_days(date, today, retention.daily) && days.insert(date) {
+                    reason = reason.or(Some("daily"));
+                }
+                if within_weeks(date, today, retention.weekly) && weeks.insert(week) {
+                    reason = reason.or(Some("weekly"));
+                }
+                if within_months(date, today, retention.monthly) && months.insert(month) {
+                    reason = reason.or(Some("monthly"));
+                }
+
+                seen_good = true;
+                good += 1;
+                planned.push((run, reason));
+            }
+        }
+
+        planned.sort_by_key(|(run, _)| Reverse(run.finished));
+        Plan { runs: planned }
+    }
+
+    pub fn pruned(&self) -> impl Iterator<Item = &Run> {
+        self.runs
+            .iter()
+            .filter(|(_, reason)| reason.is_none())
+            .map(|(run, _)| run)
+    }
+
+    /// Deletes every pruned backup's files and its manifest. Files still
+    /// listed by a kept backup, such as a database synced into every run,
+    /// are left alone.
+    pub fn prune(&self) -> anyhow::Result<()> {
+        let kept: HashSet<&PathBuf> = self
+            .runs
+            .iter()
+            .filter(|(_, reason)| reason.is_some())
+            .flat_map(|(run, _)| &run.files)
+            .collect();
+
+        for run in self.pruned() {
+            for file in &run.files {
+                if kept.contains(file) || !file.exists() {
+                    continue;
+                }
+                fs::remove_file(file)?;
+                remove_empty_parents(file, &run.manifest);
+            }
+            fs::remove_file(&run.manifest)?;
+            log::info!("Pruned {}", run.manifest.display());
+        }
+        Ok(())
+    }
+}
+
+fn within_days(date: NaiveDate, today: NaiveDate, days: usize) -> bool {
+    days > 0 && date > today - Duration::days(days as i64)
+}
+
+fn within_weeks(date: NaiveDate, today: NaiveDate, weeks: usize) -> bool {
+    let monday = today - Duration::days(today.weekday().num_days_from_monday() as i64);
+    weeks > 0 && date >= monday - Duration::weeks(weeks as i64 - 1)
+}
+
+fn within_months(date: NaiveDate, today: NaiveDate, months: usize) -> bool {
+    let index = |date: NaiveDate| date.year() as i64 * 12 + date.month0() as i64;
+    months > 0 && index(date) > index(today) - months as i64
+}
+
+/// Removes the directories `file` was in that are now empty, such as those
+/// of Parquet partitions, up to the directory of `manifest`.
+fn remove_empty_parents(file: &Path, manifest: &Path) {
+    let root = manifest.parent().unwrap_or_else(|| Path::new(""));
+    let mut directory = file.parent();
+    while let Some(path) = directory {
+        if path == root || !path.starts_with(root) || fs::remove_dir(path).is_err() {
+            break;
+        }
+        directory = path.parent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn local(day: u32, hour: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2021, 6, day, hour, 0, 0).unwrap()
+    }
+
+    fn run(username: &str, day: u32, hour: u32, complete: bool) -> Run {
+        Run {
+            manifest: PathBuf::from(format!("{}-{}-{}.json", username, day, hour)),
+            username: username.to_string(),
+            friend_of: None,
+            finished: local(day, hour).with_timezone(&Utc),
+            complete,
+            files: Vec::new(),
+        }
+    }
+
+    fn reasons(plan: &Plan) -> Vec<(String, Option<&'static str>)> {
+        plan.runs
+            .iter()
+            .map(|(run, reason)| (run.manifest.to_string_lossy().into_owned(), *reason))
+            .collect()
+    }
+
+    #[test]
+    fn the_newest_complete_run_is_always_kept() {
+        let runs = vec![
+            run("alice", 1, 12, true),
+            run("alice", 3, 12, true),
+            run("alice", 4, 12, false),
+        ];
+        let plan = Plan::new(runs, &Retention::default(), local(30, 12));
+
+        assert_eq!(
+            reasons(&plan),
+            vec![
+                (
+                    "alice-4-12.json".to_string(),
+                    Some("newer than every complete backup")
+                ),
+                (
+                    "alice-3-12.json".to_string(),
+                    Some("newest complete backup")
+                ),
+                ("alice-1-12.json".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn incomplete_runs_only_count_when_newer_than_every_complete_one() {
+        let runs = vec![
+            run("alice", 1, 12, false),
+            run("alice", 2, 12, true),
+            run("alice", 3, 12, true),
+        ];
+        let plan = Plan::new(
+            runs,
+            &Retention {
+                last: 2,
+                ..Default::default()
+            },
+            local(30, 12),
+        );
+
+        assert_eq!(
+            reasons(&plan),
+            vec![
+                (
+                    "alice-3-12.json".to_string(),
+                    Some("newest complete backup")
+                ),
+                ("alice-2-12.json".to_string(), Some("last")),
+                ("alice-1-12.json".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn daily_keeps_the_newest_run_of_each_recent_day() {
+        let runs = vec![
+            run("alice", 27, 9, true),
+            run("alice", 28, 9, true),
+            run("alice", 28, 18, true),
+            run("alice", 29, 9, true),
+            run("alice", 29, 18, true),
+        ];
+        let retention = Retention {
+            daily: 3,
+            ..Default::default()
+        };
+        let plan = Plan::new(runs, &retention, local(30, 12));
+
+        assert_eq!(
+            reasons(&plan),
+            vec![
+                (
+                    "alice-29-18.json".to_string(),
+                    Some("newest complete backup")
+                ),
+                ("alice-29-9.json".to_string(), None),
+                ("alice-28-18.json".to_string(), Some("daily")),
+                ("alice-28-9.json".to_string(), None),
+                ("alice-27-9.json".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn users_are_judged_separately() {
+        let runs = vec![run("alice", 1, 12, true), run("bob", 2, 12, true)];
+        let plan = Plan::new(runs, &Retention::default(), local(30, 12));
+
+        assert!(plan.pruned().next().is_none());
+    }
+
+    #[test]
+    fn friends_backups_are_judged_apart_from_the_users_own() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = |name: &str, friend_of: Option<&str>| {
+            let filename = dir.path().join(format!("{}-manifest.json", name));
+            crate::verify::write_manifest(&filename, "bob", friend_of, &[], true).unwrap();
+        };
+        // Bob's own backup, then a newer copy alice made of bob as a friend
+        manifest("bob", None);
+        manifest("alice-bob", Some("alice"));
+
+        let runs = find_runs(dir.path(), false).unwrap();
+        assert_eq!(runs.len(), 2);
+        let retention = Retention {
+            last: 1,
+            ..Default::default()
+        };
+        let plan = Plan::new(runs, &retention, Local::now());
+
+        // Bob's own backup is never pruned in favour of alice's copy
+        assert!(plan.pruned().next().is_none());
+        let own = plan
+            .runs
+            .iter()
+            .find(|(run, _)| run.friend_of.is_none())
+            .unwrap();
+        assert_eq!(own.1, Some("newest complete backup"));
+    }
+}
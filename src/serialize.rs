@@ -1,26 +1,641 @@
-use std::fs::File;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 
+use age::stream::StreamWriter;
+use anyhow::anyhow;
+use clap::ArgEnum;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
 use serde::Serialize;
+use tempfile::{NamedTempFile, TempDir};
 
-pub fn write_json<T: Serialize>(filename: String, data: T) -> anyhow::Result<()> {
-    let file = File::create(filename)?;
-    // TODO: Optional pretty output
-    serde_json::to_writer_pretty(file, &data)?;
+use super::encryption::{self, Decryption, Encryption};
+use super::naming;
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Appended to the name of every file written with this compression.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    /// Works out how a file is compressed from its first few bytes.
+    pub fn detect(magic: &[u8]) -> Option<Self> {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+}
+
+/// How files are written to disk on top of their format.
+#[derive(Clone, Debug, Default)]
+pub struct Encoding {
+    pub compression: Option<Compression>,
+    pub encryption: Option<Encryption>,
+}
+
+impl Encoding {
+    /// Appended to the name of every file written with this encoding, e.g.
+    /// `.gz.age`.
+    pub fn extension(&self) -> String {
+        let mut extension = String::new();
+        if let Some(compression) = self.compression {
+            extension.push_str(compression.extension());
+        }
+        if self.encryption.is_some() {
+            extension.push_str(encryption::EXTENSION);
+        }
+        extension
+    }
+}
+
+/// A file written under a temporary name next to where it belongs, and only
+/// renamed into place once it's complete and synced to disk. Until then,
+/// whatever was there before is left alone, and dropping an unfinished file
+/// deletes it.
+pub struct AtomicFile {
+    file: NamedTempFile,
+    filename: PathBuf,
+}
+
+impl AtomicFile {
+    pub fn create<P: AsRef<Path>>(filename: P) -> io::Result<Self> {
+        let filename = filename.as_ref();
+        naming::create_parent_dir(filename)?;
+        let file = tempfile::Builder::new()
+            .prefix(".hatchery-")
+            .suffix(".tmp")
+            .tempfile_in(parent_or_current(filename))?;
+
+        // Temporary files are only readable by their owner, unlike the
+        // files they replace
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let permissions = match fs::metadata(filename) {
+                Ok(metadata) => metadata.permissions(),
+                Err(_) => fs::Permissions::from_mode(0o644),
+            };
+            file.as_file().set_permissions(permissions)?;
+        }
+
+        Ok(AtomicFile {
+            file,
+            filename: filename.to_path_buf(),
+        })
+    }
+
+    /// Where the file is being written until it's persisted.
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    /// Syncs the file to disk and renames it into place.
+    pub fn persist(self) -> io::Result<()> {
+        self.file.as_file().sync_all()?;
+        self.file.persist(&self.filename).map_err(|e| e.error)?;
+        sync_parent_dir(&self.filename)
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// A directory filled in under a temporary name next to where it belongs,
+/// and only swapped in for whatever was there once it's complete, so that
+/// nothing left over from before survives. Dropping an unfinished directory
+/// deletes it.
+pub struct AtomicDir {
+    directory: TempDir,
+    path: PathBuf,
+}
+
+impl AtomicDir {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        naming::create_parent_dir(path)?;
+        let directory = tempfile::Builder::new()
+            .prefix(".hatchery-")
+            .suffix(".tmp")
+            .tempdir_in(parent_or_current(path))?;
+
+        // Temporary directories are only readable by their owner
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(directory.path(), fs::Permissions::from_mode(0o755))?;
+        }
+
+        Ok(AtomicDir {
+            directory,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Where the directory is being filled in until it's persisted.
+    pub fn path(&self) -> &Path {
+        self.directory.path()
+    }
+
+    /// Replaces whatever was at the directory's path with it.
+    pub fn persist(self) -> io::Result<()> {
+        let staged = self.directory.path();
+        // Whatever was there is moved out of the way first, so that the path
+        // only ever holds the old files or the new ones
+        let old = match fs::symlink_metadata(&self.path) {
+            Ok(metadata) => {
+                let mut old = staged.as_os_str().to_owned();
+                old.push(".old");
+                let old = PathBuf::from(old);
+                fs::rename(&self.path, &old)?;
+                Some((old, metadata.is_dir()))
+            }
+            Err(_) => None,
+        };
+        if let Err(e) = fs::rename(staged, &self.path) {
+            if let Some((old, _)) = &old {
+                let _ = fs::rename(old, &self.path);
+            }
+            return Err(e);
+        }
+        // Now in place, so it mustn't be deleted
+        self.directory.into_path();
+        match old {
+            Some((old, true)) => fs::remove_dir_all(old)?,
+            Some((old, false)) => fs::remove_file(old)?,
+            None => {}
+        }
+        sync_parent_dir(&self.path)
+    }
+}
+
+/// The directory `path` is in, which is the current one for bare names.
+fn parent_or_current(path: &Path) -> &Path {
+    match path.parent() {
+        Some(directory) if directory != Path::new("") => directory,
+        _ => Path::new("."),
+    }
+}
+
+/// Syncs the directory `filename` is in, so that a rename into it survives a
+/// crash. Only possible on Unix.
+fn sync_parent_dir(filename: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(parent_or_current(filename))?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = filename;
     Ok(())
 }
 
-#[allow(dead_code)]
-pub fn write_csv<T: Serialize>(filename: String, data: &[T]) -> anyhow::Result<()> {
-    let file = File::create(filename)?;
-    let mut csv_writer = csv::Writer::from_writer(file);
+/// The file on disk.
+enum Target {
+    Atomic(AtomicFile),
+    /// Written under a visible `.partial` name, for files that are meant to
+    /// be read while they're being written, and renamed to `filename` once
+    /// complete
+    Partial {
+        file: File,
+        filename: PathBuf,
+    },
+}
+
+impl Target {
+    fn commit(self) -> io::Result<()> {
+        match self {
+            Target::Atomic(file) => file.persist(),
+            Target::Partial { file, filename } => {
+                file.sync_all()?;
+                fs::rename(partial_filename(&filename), &filename)?;
+                sync_parent_dir(&filename)
+            }
+        }
+    }
+}
+
+/// Where a file written with `Output::create_partial` is until it's
+/// complete.
+fn partial_filename(filename: &Path) -> PathBuf {
+    let mut partial = filename.as_os_str().to_owned();
+    partial.push(".partial");
+    PathBuf::from(partial)
+}
+
+impl Write for Target {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Target::Atomic(file) => file.write(buf),
+            Target::Partial { file, .. } => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Target::Atomic(file) => file.flush(),
+            Target::Partial { file, .. } => file.flush(),
+        }
+    }
+}
+
+/// The file itself, under any compression.
+enum Destination {
+    Plain(BufWriter<Target>),
+    Encrypted(StreamWriter<BufWriter<Target>>),
+}
 
+impl Destination {
+    fn finish(self) -> io::Result<BufWriter<Target>> {
+        match self {
+            Destination::Plain(file) => Ok(file),
+            Destination::Encrypted(writer) => writer.finish(),
+        }
+    }
+}
+
+impl Write for Destination {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Destination::Plain(file) => file.write(buf),
+            Destination::Encrypted(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Destination::Plain(file) => file.flush(),
+            Destination::Encrypted(writer) => writer.flush(),
+        }
+    }
+}
+
+enum Compressor {
+    Plain(Destination),
+    Gzip(GzEncoder<Destination>),
+    Zstd(zstd::Encoder<'static, Destination>),
+}
+
+/// A file being written, compressed and encrypted as asked. Nothing appears
+/// under its name until it's finished.
+pub struct Output {
+    writer: Compressor,
+}
+
+impl Output {
+    /// Creates `filename`, with the extension of `encoding` added.
+    pub fn create(filename: String, encoding: &Encoding) -> anyhow::Result<Self> {
+        let file = AtomicFile::create(filename + &encoding.extension())?;
+        Self::new(Target::Atomic(file), encoding)
+    }
+
+    /// Like `create`, but writes to a visible `.partial` file next to
+    /// `filename`, so that it can be read while it's being written. A crash
+    /// leaves the `.partial` file cut short, but whatever was at `filename`
+    /// is left alone.
+    pub fn create_partial(filename: String, encoding: &Encoding) -> anyhow::Result<Self> {
+        let filename = PathBuf::from(filename + &encoding.extension());
+        naming::create_parent_dir(&filename)?;
+        let file = File::create(partial_filename(&filename))?;
+        Self::new(Target::Partial { file, filename }, encoding)
+    }
+
+    fn new(target: Target, encoding: &Encoding) -> anyhow::Result<Self> {
+        let file = BufWriter::new(target);
+        let destination = match &encoding.encryption {
+            Some(encryption) => Destination::Encrypted(encryption.wrap(file)?),
+            None => Destination::Plain(file),
+        };
+        let writer = match encoding.compression {
+            None => Compressor::Plain(destination),
+            Some(Compression::Gzip) => {
+                Compressor::Gzip(GzEncoder::new(destination, Default::default()))
+            }
+            Some(Compression::Zstd) => Compressor::Zstd(zstd::Encoder::new(destination, 0)?),
+        };
+        Ok(Output { writer })
+    }
+
+    /// Writes out whatever is left, including the trailers of compressed and
+    /// encrypted files, and syncs the file to disk under its name.
+    pub fn finish(self) -> anyhow::Result<()> {
+        let destination = match self.writer {
+            Compressor::Plain(destination) => destination,
+            Compressor::Gzip(encoder) => encoder.finish()?,
+            Compressor::Zstd(encoder) => encoder.finish()?,
+        };
+        destination
+            .finish()?
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .commit()?;
+        Ok(())
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.writer {
+            Compressor::Plain(destination) => destination.write(buf),
+            Compressor::Gzip(encoder) => encoder.write(buf),
+            Compressor::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.writer {
+            Compressor::Plain(destination) => destination.flush(),
+            Compressor::Gzip(encoder) => encoder.flush(),
+            Compressor::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Opens `filename` for reading, decrypting and decompressing it as needed.
+pub fn open(filename: &Path, decryption: &Decryption) -> anyhow::Result<Box<dyn Read>> {
+    let mut file: Box<dyn BufRead> = Box::new(BufReader::new(File::open(filename)?));
+    if file.fill_buf()?.starts_with(encryption::MAGIC) {
+        file = Box::new(BufReader::new(decryption.wrap(file).map_err(|e| {
+            anyhow!("Failed to decrypt {}: {}", filename.display(), e)
+        })?));
+    }
+    Ok(match Compression::detect(file.fill_buf()?) {
+        None => file,
+        Some(Compression::Gzip) => Box::new(MultiGzDecoder::new(file)),
+        Some(Compression::Zstd) => Box::new(zstd::Decoder::with_buffer(file)?),
+    })
+}
+
+/// Whether `filename` is compressed or encrypted, going by its first bytes.
+pub fn is_encoded(filename: &Path) -> anyhow::Result<bool> {
+    let mut magic = Vec::new();
+    File::open(filename)?
+        .take(encryption::MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    Ok(magic.starts_with(encryption::MAGIC) || Compression::detect(&magic).is_some())
+}
+
+/// `filename` without the extensions of any compression or encryption, e.g.
+/// `backup.tar` for `backup.tar.gz.age`.
+pub fn strip_encoding(filename: &Path) -> PathBuf {
+    let mut filename = filename.to_path_buf();
+    for extensions in [&["age"][..], &["gz", "zst"]] {
+        let extension = filename
+            .extension()
+            .and_then(|extension| extension.to_str());
+        if matches!(extension, Some(extension) if extensions.contains(&extension)) {
+            filename.set_extension("");
+        }
+    }
+    filename
+}
+
+/// A file that can be opened by name as if it were plain. Compressed or
+/// encrypted files are decoded to a temporary copy first, for libraries such
+/// as SQLite that can only open plain files.
+///
+/// Copies are plaintext, so they're kept in a temporary directory only the
+/// user can open, along with anything SQLite writes next to them.
+pub struct DecodedFile {
+    path: PathBuf,
+    /// Deletes the copy when dropped
+    _directory: Option<TempDir>,
+}
+
+impl DecodedFile {
+    pub fn new(filename: &Path, decryption: &Decryption) -> anyhow::Result<Self> {
+        if !is_encoded(filename)? {
+            return Ok(DecodedFile {
+                path: filename.to_path_buf(),
+                _directory: None,
+            });
+        }
+
+        Self::copy(filename, decryption)
+    }
+
+    /// Decodes `filename` into a temporary copy even if it isn't encoded,
+    /// for when the copy is going to be changed.
+    pub fn copy(filename: &Path, decryption: &Decryption) -> anyhow::Result<Self> {
+        let directory = tempfile::Builder::new().prefix("hatchery-").tempdir()?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(directory.path(), fs::Permissions::from_mode(0o700))?;
+        }
+
+        let name = strip_encoding(filename)
+            .file_name()
+            .map(|name| name.to_os_string())
+            .unwrap_or_else(|| "decoded".into());
+        let path = directory.path().join(name);
+        io::copy(&mut open(filename, decryption)?, &mut File::create(&path)?)?;
+        Ok(DecodedFile {
+            path,
+            _directory: Some(directory),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+pub fn write_json<T: Serialize>(
+    filename: String,
+    data: T,
+    pretty: bool,
+    encoding: &Encoding,
+) -> anyhow::Result<()> {
+    let mut file = Output::create(filename, encoding)?;
+    if pretty {
+        serde_json::to_writer_pretty(&mut file, &data)?;
+    } else {
+        serde_json::to_writer(&mut file, &data)?;
+    }
+    file.finish()
+}
+
+/// Writes records as newline-delimited JSON, one per line, as they're
+/// handed over. Written to a `.partial` file that can be followed while it's
+/// written, and only renamed into place once finished.
+pub struct NdjsonWriter {
+    writer: Output,
+}
+
+impl NdjsonWriter {
+    pub fn create(filename: String, encoding: &Encoding) -> anyhow::Result<Self> {
+        Ok(NdjsonWriter {
+            writer: Output::create_partial(filename, encoding)?,
+        })
+    }
+
+    /// Writes `records` and flushes them, so that whoever is reading the
+    /// file sees them straight away.
+    pub fn write<T: Serialize>(&mut self, records: &[T]) -> anyhow::Result<()> {
+        for record in records {
+            serde_json::to_writer(&mut self.writer, record)?;
+            self.writer.write_all(b"\n")?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    pub fn finish(self) -> anyhow::Result<()> {
+        self.writer.finish()
+    }
+}
+
+/// Writes records as newline-delimited JSON all at once. Unlike
+/// `NdjsonWriter`, nothing can be read until it's complete.
+pub fn write_ndjson<T: Serialize>(
+    filename: String,
+    data: &[T],
+    encoding: &Encoding,
+) -> anyhow::Result<()> {
+    let mut file = Output::create(filename, encoding)?;
     for record in data {
-        let r = csv_writer.serialize(record);
-        if let Err(e) = r {
-            eprintln!("{}", e);
+        serde_json::to_writer(&mut file, record)?;
+        file.write_all(b"\n")?;
+    }
+    file.finish()
+}
+
+/// Writes one row per record, with a header row taken from the field names.
+/// Records must be flat, and the first one that can't be written fails the
+/// whole file.
+pub fn write_csv<T: Serialize>(
+    filename: String,
+    data: &[T],
+    encoding: &Encoding,
+) -> anyhow::Result<()> {
+    let file = Output::create(filename.clone(), encoding)?;
+    let mut csv_writer = csv::Writer::from_writer(file);
+
+    for (i, record) in data.iter().enumerate() {
+        csv_writer
+            .serialize(record)
+            .map_err(|e| anyhow!("Failed to write row {} of {}: {}", i + 1, filename, e))?;
+    }
+
+    csv_writer
+        .into_inner()
+        .map_err(|e| anyhow!("Failed to write {}: {}", filename, e.error()))?
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compressed_files_read_back_as_written() {
+        let dir = tempfile::tempdir().unwrap();
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let encoding = Encoding {
+                compression: Some(compression),
+                encryption: None,
+            };
+            let filename = dir.path().join("scrobbles.ndjson");
+            write_ndjson(
+                filename.to_string_lossy().into_owned(),
+                &["one", "two"],
+                &encoding,
+            )
+            .unwrap();
+
+            let filename = dir
+                .path()
+                .join(format!("scrobbles.ndjson{}", compression.extension()));
+            assert!(is_encoded(&filename).unwrap());
+            assert_eq!(
+                strip_encoding(&filename),
+                dir.path().join("scrobbles.ndjson")
+            );
+            let mut contents = String::new();
+            open(&filename, &Decryption::default())
+                .unwrap()
+                .read_to_string(&mut contents)
+                .unwrap();
+            assert_eq!(contents, "\"one\"\n\"two\"\n");
         }
     }
 
-    csv_writer.flush()?;
-    Ok(())
+    #[test]
+    fn decoded_copies_are_private() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("backup.json");
+        let encoding = Encoding {
+            compression: Some(Compression::Gzip),
+            encryption: None,
+        };
+        write_json(
+            filename.to_string_lossy().into_owned(),
+            "plain",
+            false,
+            &encoding,
+        )
+        .unwrap();
+
+        let decoded =
+            DecodedFile::new(&dir.path().join("backup.json.gz"), &Decryption::default()).unwrap();
+        assert_eq!(fs::read_to_string(decoded.path()).unwrap(), "\"plain\"");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let directory = fs::metadata(decoded.path().parent().unwrap()).unwrap();
+            assert_eq!(directory.permissions().mode() & 0o777, 0o700);
+        }
+    }
+
+    #[test]
+    fn streams_only_replace_the_old_file_once_finished() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("scrobbles.ndjson");
+        fs::write(&filename, "\"old\"\n").unwrap();
+        let stream = || {
+            NdjsonWriter::create(
+                filename.to_string_lossy().into_owned(),
+                &Encoding::default(),
+            )
+            .unwrap()
+        };
+
+        // Dropped part way, as if the run had crashed
+        let mut writer = stream();
+        writer.write(&["new"]).unwrap();
+        drop(writer);
+        assert_eq!(fs::read_to_string(&filename).unwrap(), "\"old\"\n");
+        assert_eq!(
+            fs::read_to_string(partial_filename(&filename)).unwrap(),
+            "\"new\"\n"
+        );
+
+        let mut writer = stream();
+        writer.write(&["new"]).unwrap();
+        writer.write(&["newer"]).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(
+            fs::read_to_string(&filename).unwrap(),
+            "\"new\"\n\"newer\"\n"
+        );
+        assert!(!partial_filename(&filename).exists());
+    }
 }
@@ -0,0 +1,983 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+
+use clap::ArgEnum;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+use super::api::*;
+use super::archive::ArchiveSink;
+use super::graph::{FriendGraph, User};
+use super::naming::{self, FileNames};
+use super::serialize::{self, AtomicFile, Encoding};
+use super::sql::*;
+use super::tags::ArtistTags;
+
+/// Everything fetched for a single user.
+#[derive(Debug, Default)]
+pub struct Backup {
+    pub username: String,
+    pub loved_tracks: Vec<LovedTrack>,
+    pub friends: Vec<Friend>,
+    pub friend_graph: FriendGraph,
+    pub scrobbles: Vec<Track>,
+    pub now_playing: Vec<Track>,
+    pub artist_tags: Vec<ArtistTags>,
+    /// Datasets that couldn't be fetched in full, by the name they're
+    /// written under (e.g. `scrobbles`)
+    pub incomplete: BTreeSet<String>,
+}
+
+/// Every dataset, by the name it's written under.
+pub const DATASETS: [&str; 7] = [
+    "loved_tracks",
+    "friends",
+    "users",
+    "friendships",
+    "scrobbles",
+    "now_playing",
+    "artist_tags",
+];
+
+/// Somewhere a backup can be written to.
+///
+/// A run starts with `begin_run`, writes each non-empty dataset once, and
+/// always ends with `finish`, even when writing a dataset failed.
+pub trait BackupSink {
+    fn begin_run(&mut self, username: &str) -> anyhow::Result<()>;
+    /// Called before any dataset is written for each one that's missing
+    /// items, even if nothing of it was fetched at all.
+    fn mark_incomplete(&mut self, _dataset: &str) {}
+    /// Called with each page of loved tracks as soon as it's fetched, before
+    /// `write_loved_tracks` is called with all of them. Only sinks that write
+    /// items as they arrive need to do anything here.
+    fn stream_loved_tracks(&mut self, _page: &[&LovedTrack]) -> anyhow::Result<()> {
+        Ok(())
+    }
+    /// Like `stream_loved_tracks`, but for scrobbles.
+    fn stream_scrobbles(&mut self, _page: &[&Track]) -> anyhow::Result<()> {
+        Ok(())
+    }
+    fn write_loved_tracks(&mut self, loved_tracks: Vec<LovedTrack>) -> anyhow::Result<()>;
+    fn write_friends(&mut self, friends: Vec<Friend>) -> anyhow::Result<()>;
+    fn write_friend_graph(&mut self, graph: FriendGraph) -> anyhow::Result<()>;
+    fn write_scrobbles(&mut self, scrobbles: Vec<Track>) -> anyhow::Result<()>;
+    fn write_now_playing(&mut self, now_playing: Vec<Track>) -> anyhow::Result<()>;
+    fn write_artist_tags(&mut self, artist_tags: Vec<ArtistTags>) -> anyhow::Result<()>;
+    /// `success` is whether every dataset was written.
+    fn finish(&mut self, success: bool) -> anyhow::Result<()>;
+    /// Every file written so far, to be listed in the run's manifest. Sinks
+    /// that don't write files have none.
+    fn written_files(&self) -> Vec<WrittenFile> {
+        Vec::new()
+    }
+}
+
+/// The kinds of file a sink can write.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileFormat {
+    Json,
+    Ndjson,
+    Csv,
+    Archive,
+    Sqlite,
+    Parquet,
+    /// Pages as Last.fm sent them, written by [`crate::raw::RawRecorder`]
+    Raw,
+}
+
+/// A file written by a sink.
+#[derive(Clone, Debug)]
+pub struct WrittenFile {
+    pub path: PathBuf,
+    pub format: FileFormat,
+    /// The dataset the file holds, unless it holds all of them
+    pub dataset: Option<String>,
+    /// How many records the file holds, if it holds a single dataset
+    pub count: Option<usize>,
+    /// Whether later runs change the file in place, so that it has no
+    /// checksum worth recording
+    pub mutable: bool,
+}
+
+impl WrittenFile {
+    pub fn dataset<P: Into<PathBuf>>(
+        path: P,
+        format: FileFormat,
+        dataset: &str,
+        count: usize,
+    ) -> Self {
+        WrittenFile {
+            path: path.into(),
+            format,
+            dataset: Some(dataset.to_string()),
+            count: Some(count),
+            mutable: false,
+        }
+    }
+}
+
+/// Settings for the sinks created by `ExportFormat::sink`. Sinks ignore
+/// whatever doesn't apply to them.
+#[derive(Clone, Debug, Default)]
+pub struct SinkOptions {
+    /// Write JSON without indentation or line breaks
+    pub compact: bool,
+    /// Split Parquet scrobbles into one file per year
+    pub partition_by_year: bool,
+    /// Compress and encrypt JSON, NDJSON and CSV files and archives
+    pub encoding: Encoding,
+}
+
+#[derive(ArgEnum, Clone, Debug)]
+pub enum ExportFormat {
+    Json,
+    Sql,
+    Csv,
+    Ndjson,
+    Archive,
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl ExportFormat {
+    /// Whether `SinkOptions::encoding` applies to this format.
+    pub fn supports_encoding(&self) -> bool {
+        matches!(
+            self,
+            ExportFormat::Json | ExportFormat::Csv | ExportFormat::Ndjson | ExportFormat::Archive
+        )
+    }
+
+    /// The name this format is given on the command line, as in `{format}`
+    /// in filename templates.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Sql => "sql",
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+            ExportFormat::Archive => "archive",
+            #[cfg(feature = "parquet")]
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+
+    /// Creates a sink writing files named by `names`.
+    pub fn sink(&self, names: &FileNames, options: &SinkOptions) -> Box<dyn BackupSink> {
+        match self {
+            ExportFormat::Json => Box::new(JsonSink::new(
+                names.clone(),
+                !options.compact,
+                options.encoding.clone(),
+            )),
+            ExportFormat::Sql => Box::new(SqliteSink::new(names.whole() + ".db")),
+            ExportFormat::Csv => Box::new(CsvSink::new(names.clone(), options.encoding.clone())),
+            ExportFormat::Ndjson => {
+                Box::new(NdjsonSink::new(names.clone(), options.encoding.clone()))
+            }
+            ExportFormat::Archive => Box::new(ArchiveSink::new(
+                names,
+                !options.compact,
+                options.encoding.clone(),
+            )),
+            #[cfg(feature = "parquet")]
+            ExportFormat::Parquet => Box::new(super::columnar::ParquetSink::new(
+                names.clone(),
+                options.partition_by_year,
+            )),
+        }
+    }
+
+    /// Every file or directory a sink of this format could write under
+    /// `names`, along with the run's manifest.
+    pub fn outputs(&self, names: &FileNames, options: &SinkOptions) -> Vec<PathBuf> {
+        let encoding = if self.supports_encoding() {
+            options.encoding.extension()
+        } else {
+            String::new()
+        };
+        let datasets = |extension: &str| -> Vec<String> {
+            DATASETS
+                .iter()
+                .map(|dataset| names.dataset(dataset) + extension + &encoding)
+                .collect()
+        };
+        let mut outputs = match self {
+            ExportFormat::Json => datasets(".json"),
+            ExportFormat::Sql => vec![names.whole() + ".db"],
+            ExportFormat::Csv => datasets(".csv"),
+            ExportFormat::Ndjson => datasets(".ndjson"),
+            ExportFormat::Archive => vec![names.whole() + ".tar" + &encoding],
+            #[cfg(feature = "parquet")]
+            ExportFormat::Parquet => {
+                // Partitioned scrobbles go in a directory
+                let mut outputs = datasets(".parquet");
+                outputs.push(names.dataset("scrobbles"));
+                outputs
+            }
+        };
+        outputs.push(names.manifest());
+        outputs.into_iter().map(PathBuf::from).collect()
+    }
+}
+
+/// Writes every non-empty dataset of `backup` to `sink` as a run of its own.
+/// Returns whether everything was written successfully.
+pub fn write_backup(sink: &mut dyn BackupSink, backup: Backup) -> bool {
+    if let Err(e) = sink.begin_run(&backup.username) {
+        log::error!("Failed to begin run: {}", e);
+        return false;
+    }
+    write_datasets(sink, backup)
+}
+
+/// Writes every non-empty dataset of `backup` to a `sink` whose run has
+/// already begun, then finishes the run. Returns whether everything was
+/// written successfully.
+pub fn write_datasets(sink: &mut dyn BackupSink, backup: Backup) -> bool {
+    let mut success = true;
+
+    for dataset in &backup.incomplete {
+        sink.mark_incomplete(dataset);
+    }
+
+    if !backup.loved_tracks.is_empty() {
+        log::debug!("Inserting loved tracks...");
+        match sink.write_loved_tracks(backup.loved_tracks) {
+            Ok(()) => log::debug!("Done!"),
+            Err(e) => {
+                success = false;
+                log::error!("Failed to write loved tracks: {}. Continuing...", e);
+            }
+        }
+    } else {
+        log::warn!("No loved tracks fetched. Skipping.");
+    }
+
+    if !backup.friends.is_empty() {
+        log::debug!("Inserting friends...");
+        match sink.write_friends(backup.friends) {
+            Ok(()) => log::debug!("Done!"),
+            Err(e) => {
+                success = false;
+                log::error!("Failed to write friends: {}. Continuing...", e);
+            }
+        }
+    } else {
+        log::warn!("No friends fetched. Skipping.");
+    }
+
+    if !backup.friend_graph.users.is_empty() {
+        log::debug!("Inserting friends graph...");
+        match sink.write_friend_graph(backup.friend_graph) {
+            Ok(()) => log::debug!("Done!"),
+            Err(e) => {
+                success = false;
+                log::error!("Failed to write friends graph: {}. Continuing...", e);
+            }
+        }
+    }
+
+    if !backup.scrobbles.is_empty() {
+        log::debug!("Inserting scrobbles...");
+        match sink.write_scrobbles(backup.scrobbles) {
+            Ok(()) => log::debug!("Done!"),
+            Err(e) => {
+                success = false;
+                log::error!("Failed to write scrobbles: {}.", e);
+            }
+        }
+    } else {
+        log::warn!("No scrobbles fetched. Skipping.");
+    }
+
+    if !backup.now_playing.is_empty() {
+        log::debug!("Inserting now playing...");
+        match sink.write_now_playing(backup.now_playing) {
+            Ok(()) => log::debug!("Done!"),
+            Err(e) => {
+                success = false;
+                log::error!("Failed to write now playing: {}.", e);
+            }
+        }
+    }
+
+    if !backup.artist_tags.is_empty() {
+        log::debug!("Inserting artist tags...");
+        match sink.write_artist_tags(backup.artist_tags) {
+            Ok(()) => log::debug!("Done!"),
+            Err(e) => {
+                success = false;
+                log::error!("Failed to write artist tags: {}.", e);
+            }
+        }
+    }
+
+    if let Err(e) = sink.finish(success) {
+        success = false;
+        log::error!("Failed to finish run: {}", e);
+    }
+    success
+}
+
+/// Writes each dataset to its own `{basename}-{dataset}.json` file, with
+/// `.gz` or `.zst` added when compressed.
+pub struct JsonSink {
+    names: FileNames,
+    pretty: bool,
+    encoding: Encoding,
+    written: Vec<WrittenFile>,
+}
+
+impl JsonSink {
+    pub fn new(names: FileNames, pretty: bool, encoding: Encoding) -> Self {
+        JsonSink {
+            names,
+            pretty,
+            encoding,
+            written: Vec::new(),
+        }
+    }
+
+    fn write<T: Serialize>(&mut self, dataset: &str, data: &[T]) -> anyhow::Result<()> {
+        let filename = self.names.dataset(dataset) + ".json";
+        serialize::write_json(filename.clone(), data, self.pretty, &self.encoding)?;
+        self.written.push(WrittenFile::dataset(
+            filename + &self.encoding.extension(),
+            FileFormat::Json,
+            dataset,
+            data.len(),
+        ));
+        Ok(())
+    }
+}
+
+impl BackupSink for JsonSink {
+    fn begin_run(&mut self, _username: &str) -> anyhow::Result<()> {
+        log::info!("Writing JSON...");
+        Ok(())
+    }
+
+    fn write_loved_tracks(&mut self, loved_tracks: Vec<LovedTrack>) -> anyhow::Result<()> {
+        self.write("loved_tracks", &loved_tracks)
+    }
+
+    fn write_friends(&mut self, friends: Vec<Friend>) -> anyhow::Result<()> {
+        self.write("friends", &friends)
+    }
+
+    fn write_friend_graph(&mut self, graph: FriendGraph) -> anyhow::Result<()> {
+        self.write("users", &graph.users)?;
+        self.write("friendships", &graph.friendships)
+    }
+
+    fn write_scrobbles(&mut self, scrobbles: Vec<Track>) -> anyhow::Result<()> {
+        self.write("scrobbles", &scrobbles)
+    }
+
+    fn write_now_playing(&mut self, now_playing: Vec<Track>) -> anyhow::Result<()> {
+        self.write("now_playing", &now_playing)
+    }
+
+    fn write_artist_tags(&mut self, artist_tags: Vec<ArtistTags>) -> anyhow::Result<()> {
+        self.write("artist_tags", &artist_tags)
+    }
+
+    fn finish(&mut self, _success: bool) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn written_files(&self) -> Vec<WrittenFile> {
+        self.written.clone()
+    }
+}
+
+/// Writes each dataset to its own `{basename}-{dataset}.ndjson` file, one
+/// JSON object per line. Loved tracks and scrobbles are written page by page
+/// as they're fetched, to `.partial` files renamed into place once complete.
+pub struct NdjsonSink {
+    names: FileNames,
+    /// Datasets being written as they're fetched. Only set once there's
+    /// something to write, so empty datasets get no file like elsewhere.
+    streams: HashMap<&'static str, serialize::NdjsonWriter>,
+    /// Datasets where writing a page failed, leaving their file incomplete
+    failed_streams: HashSet<&'static str>,
+    /// How many records have been written to each stream
+    streamed: HashMap<&'static str, usize>,
+    encoding: Encoding,
+    written: Vec<WrittenFile>,
+}
+
+impl NdjsonSink {
+    pub fn new(names: FileNames, encoding: Encoding) -> Self {
+        NdjsonSink {
+            names,
+            streams: HashMap::new(),
+            failed_streams: HashSet::new(),
+            streamed: HashMap::new(),
+            encoding,
+            written: Vec::new(),
+        }
+    }
+
+    fn filename(&self, dataset: &str) -> String {
+        self.names.dataset(dataset) + ".ndjson"
+    }
+
+    fn record_written(&mut self, dataset: &str, count: usize) {
+        let filename = self.filename(dataset) + &self.encoding.extension();
+        self.written.push(WrittenFile::dataset(
+            filename,
+            FileFormat::Ndjson,
+            dataset,
+            count,
+        ));
+    }
+
+    fn stream<T: Serialize>(&mut self, dataset: &'static str, page: &[T]) -> anyhow::Result<()> {
+        if page.is_empty() {
+            return Ok(());
+        }
+        if !self.streams.contains_key(dataset) {
+            let writer = serialize::NdjsonWriter::create(self.filename(dataset), &self.encoding)?;
+            self.streams.insert(dataset, writer);
+        }
+        let result = match self.streams.get_mut(dataset) {
+            Some(writer) => writer.write(page),
+            None => Ok(()),
+        };
+        match result {
+            Ok(()) => *self.streamed.entry(dataset).or_default() += page.len(),
+            Err(_) => {
+                self.failed_streams.insert(dataset);
+            }
+        }
+        result
+    }
+
+    fn write<T: Serialize>(&mut self, dataset: &'static str, data: &[T]) -> anyhow::Result<()> {
+        // Already written as it was fetched
+        if let Some(writer) = self.streams.remove(dataset) {
+            // A stream missing pages is left under its `.partial` name
+            // rather than replacing whatever was there
+            if self.failed_streams.contains(dataset) {
+                return Err(anyhow::anyhow!(
+                    "Some {} couldn't be written as they arrived",
+                    dataset
+                ));
+            }
+            writer.finish()?;
+            let count = self.streamed.get(dataset).copied().unwrap_or_default();
+            self.record_written(dataset, count);
+            return Ok(());
+        }
+        serialize::write_ndjson(self.filename(dataset), data, &self.encoding)?;
+        self.record_written(dataset, data.len());
+        Ok(())
+    }
+}
+
+impl BackupSink for NdjsonSink {
+    fn begin_run(&mut self, _username: &str) -> anyhow::Result<()> {
+        log::info!("Writing NDJSON...");
+        Ok(())
+    }
+
+    fn stream_loved_tracks(&mut self, page: &[&LovedTrack]) -> anyhow::Result<()> {
+        self.stream("loved_tracks", page)
+    }
+
+    fn stream_scrobbles(&mut self, page: &[&Track]) -> anyhow::Result<()> {
+        self.stream("scrobbles", page)
+    }
+
+    fn write_loved_tracks(&mut self, loved_tracks: Vec<LovedTrack>) -> anyhow::Result<()> {
+        self.write("loved_tracks", &loved_tracks)
+    }
+
+    fn write_friends(&mut self, friends: Vec<Friend>) -> anyhow::Result<()> {
+        self.write("friends", &friends)
+    }
+
+    fn write_friend_graph(&mut self, graph: FriendGraph) -> anyhow::Result<()> {
+        self.write("users", &graph.users)?;
+        self.write("friendships", &graph.friendships)
+    }
+
+    fn write_scrobbles(&mut self, scrobbles: Vec<Track>) -> anyhow::Result<()> {
+        self.write("scrobbles", &scrobbles)
+    }
+
+    fn write_now_playing(&mut self, now_playing: Vec<Track>) -> anyhow::Result<()> {
+        self.write("now_playing", &now_playing)
+    }
+
+    fn write_artist_tags(&mut self, artist_tags: Vec<ArtistTags>) -> anyhow::Result<()> {
+        self.write("artist_tags", &artist_tags)
+    }
+
+    fn finish(&mut self, _success: bool) -> anyhow::Result<()> {
+        // Datasets that failed to fetch part way through were never handed
+        // over in full, so whatever arrived is all there is
+        let streams: Vec<_> = self.streams.drain().collect();
+        for (dataset, writer) in streams {
+            if !self.failed_streams.contains(dataset) {
+                writer.finish()?;
+                let count = self.streamed.get(dataset).copied().unwrap_or_default();
+                self.record_written(dataset, count);
+            }
+        }
+        Ok(())
+    }
+
+    fn written_files(&self) -> Vec<WrittenFile> {
+        self.written.clone()
+    }
+}
+
+/// Writes every dataset to a SQLite database as a new run, creating or
+/// upgrading the database as needed.
+///
+/// The whole run is a single transaction, committed once the run finishes,
+/// so a crash leaves the database as it was. A new database is written under
+/// a temporary name and only renamed into place then.
+pub struct SqliteSink {
+    filename: PathBuf,
+    /// The new database the run is written to, which is renamed into place
+    /// once the run finishes. Unused when syncing
+    copy: Option<AtomicFile>,
+    conn: Option<Connection>,
+    run: i64,
+    /// Whether the database is synced into by every run
+    sync: bool,
+    incomplete: BTreeSet<String>,
+}
+
+impl SqliteSink {
+    pub fn new<P: Into<PathBuf>>(filename: P) -> Self {
+        SqliteSink {
+            filename: filename.into(),
+            copy: None,
+            conn: None,
+            run: 0,
+            sync: false,
+            incomplete: BTreeSet::new(),
+        }
+    }
+
+    /// A sink for a database that every run syncs into, as given with
+    /// `--sync`.
+    pub fn sync<P: Into<PathBuf>>(filename: P) -> Self {
+        SqliteSink {
+            sync: true,
+            ..SqliteSink::new(filename)
+        }
+    }
+
+    fn conn(&mut self) -> anyhow::Result<&mut Connection> {
+        self.conn
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Run has not begun"))
+    }
+}
+
+impl BackupSink for SqliteSink {
+    fn begin_run(&mut self, username: &str) -> anyhow::Result<()> {
+        log::info!("Writing database...");
+        // Synced databases can be huge, so they're written in place rather
+        // than copied every run
+        let copy = if self.sync {
+            naming::create_parent_dir(&self.filename)?;
+            None
+        } else {
+            let copy = AtomicFile::create(&self.filename)?;
+            if self.filename.exists() {
+                fs::copy(&self.filename, copy.path())?;
+            }
+            Some(copy)
+        };
+        let path = copy
+            .as_ref()
+            .map_or(self.filename.as_path(), AtomicFile::path);
+        let mut conn = open_db(&path.to_string_lossy())
+            .map_err(|e| anyhow::anyhow!("Failed to open DB. Check the provided path. ({})", e))?;
+
+        log::debug!("Migrating database...");
+        migrate(&mut conn)?;
+
+        conn.execute_batch("BEGIN")?;
+        self.run = begin_run(&mut conn, username)?;
+        self.conn = Some(conn);
+        self.copy = copy;
+        Ok(())
+    }
+
+    fn mark_incomplete(&mut self, dataset: &str) {
+        self.incomplete.insert(dataset.to_string());
+    }
+
+    fn write_loved_tracks(&mut self, loved_tracks: Vec<LovedTrack>) -> anyhow::Result<()> {
+        let run = self.run;
+        Ok(insert_loved_tracks(self.conn()?, run, loved_tracks)?)
+    }
+
+    fn write_friends(&mut self, friends: Vec<Friend>) -> anyhow::Result<()> {
+        let run = self.run;
+        Ok(insert_friends(self.conn()?, run, friends)?)
+    }
+
+    fn write_friend_graph(&mut self, graph: FriendGraph) -> anyhow::Result<()> {
+        let run = self.run;
+        Ok(insert_friend_graph(self.conn()?, run, graph)?)
+    }
+
+    fn write_scrobbles(&mut self, scrobbles: Vec<Track>) -> anyhow::Result<()> {
+        let run = self.run;
+        let complete = !self.incomplete.contains("scrobbles");
+        let conn = self.conn()?;
+
+        log::debug!("Looking for edited and deleted scrobbles...");
+        match record_scrobble_changes(conn, run, &scrobbles, complete)? {
+            0 => log::debug!("None found."),
+            changes => log::info!(
+                "Found {} edited or deleted scrobbles. See `hatchery changes`.",
+                changes
+            ),
+        }
+
+        Ok(insert_scrobbles(conn, run, scrobbles)?)
+    }
+
+    fn write_now_playing(&mut self, now_playing: Vec<Track>) -> anyhow::Result<()> {
+        let run = self.run;
+        Ok(insert_now_playing(self.conn()?, run, now_playing)?)
+    }
+
+    fn write_artist_tags(&mut self, artist_tags: Vec<ArtistTags>) -> anyhow::Result<()> {
+        Ok(insert_artist_tags(self.conn()?, artist_tags)?)
+    }
+
+    fn finish(&mut self, success: bool) -> anyhow::Result<()> {
+        let mut conn = match self.conn.take() {
+            Some(conn) => conn,
+            None => return Ok(()),
+        };
+
+        // Only runs that wrote everything are marked as finished, but
+        // whatever was written is kept either way
+        if success {
+            finish_run(&mut conn, self.run)?;
+        }
+        conn.execute_batch("COMMIT")?;
+        close_db(conn)?;
+        if let Some(copy) = self.copy.take() {
+            copy.persist()?;
+        }
+        log::info!("Finished writing database.");
+        Ok(())
+    }
+
+    fn written_files(&self) -> Vec<WrittenFile> {
+        // Nothing was written if the run never began
+        if self.run == 0 {
+            return Vec::new();
+        }
+        // Counts aren't known, since the database may hold earlier runs
+        vec![WrittenFile {
+            path: self.filename.clone(),
+            format: FileFormat::Sqlite,
+            dataset: None,
+            count: None,
+            mutable: self.sync,
+        }]
+    }
+}
+
+// CSV rows. The column layout is documented in the readme, so keep the two
+// in sync.
+
+/// A scrobble or now playing track.
+#[derive(Serialize)]
+struct TrackRow<'a> {
+    timestamp: Option<i64>,
+    date: Option<&'a str>,
+    name: &'a str,
+    mbid: Option<&'a str>,
+    url: &'a str,
+    artist: &'a str,
+    artist_mbid: Option<&'a str>,
+    album: Option<&'a str>,
+    album_mbid: Option<&'a str>,
+}
+
+impl<'a> From<&'a Track> for TrackRow<'a> {
+    fn from(track: &'a Track) -> Self {
+        TrackRow {
+            timestamp: track.date.as_ref().map(|date| date.datetime.timestamp()),
+            date: track.date.as_ref().map(|date| date.pretty_string.as_str()),
+            name: &track.name,
+            mbid: track.mbid.as_deref(),
+            url: &track.url,
+            artist: &track.artist.name,
+            artist_mbid: track.artist.mbid.as_deref(),
+            album: track.album.as_ref().map(|album| album.name.as_str()),
+            album_mbid: track.album.as_ref().and_then(|album| album.mbid.as_deref()),
+        }
+    }
+}
+
+/// A loved track. Artist URLs are only known for these.
+#[derive(Serialize)]
+struct LovedTrackRow<'a> {
+    timestamp: Option<i64>,
+    date: Option<&'a str>,
+    name: &'a str,
+    mbid: Option<&'a str>,
+    url: &'a str,
+    artist: &'a str,
+    artist_mbid: Option<&'a str>,
+    artist_url: Option<&'a str>,
+}
+
+impl<'a> From<&'a LovedTrack> for LovedTrackRow<'a> {
+    fn from(track: &'a LovedTrack) -> Self {
+        LovedTrackRow {
+            timestamp: track.date.as_ref().map(|date| date.datetime.timestamp()),
+            date: track.date.as_ref().map(|date| date.pretty_string.as_str()),
+            name: &track.name,
+            mbid: track.mbid.as_deref(),
+            url: &track.url,
+            artist: &track.artist.name,
+            artist_mbid: track.artist.mbid.as_deref(),
+            artist_url: track.artist.url.as_deref(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FriendRow<'a> {
+    name: &'a str,
+    real_name: Option<&'a str>,
+    country: Option<&'a str>,
+    subscriber: Option<bool>,
+    registered: Option<i64>,
+    url: Option<&'a str>,
+}
+
+impl<'a> From<&'a Friend> for FriendRow<'a> {
+    fn from(friend: &'a Friend) -> Self {
+        FriendRow {
+            name: &friend.name,
+            real_name: friend.real_name.as_deref(),
+            country: friend.country.as_deref(),
+            subscriber: Some(friend.subscriber),
+            registered: Some(friend.registered.datetime.timestamp()),
+            url: Some(&friend.url),
+        }
+    }
+}
+
+/// A user in the friends graph. Profile columns are empty for the backed up
+/// account itself.
+#[derive(Serialize)]
+struct UserRow<'a> {
+    name: &'a str,
+    depth: usize,
+    real_name: Option<&'a str>,
+    country: Option<&'a str>,
+    subscriber: Option<bool>,
+    registered: Option<i64>,
+    url: Option<&'a str>,
+}
+
+impl<'a> From<&'a User> for UserRow<'a> {
+    fn from(user: &'a User) -> Self {
+        // Only the backed up account itself has no profile
+        let profile = user.profile.as_ref().map(FriendRow::from);
+        UserRow {
+            name: &user.name,
+            depth: user.depth,
+            real_name: profile.as_ref().and_then(|profile| profile.real_name),
+            country: profile.as_ref().and_then(|profile| profile.country),
+            subscriber: profile.as_ref().and_then(|profile| profile.subscriber),
+            registered: profile.as_ref().and_then(|profile| profile.registered),
+            url: profile.as_ref().and_then(|profile| profile.url),
+        }
+    }
+}
+
+/// A single tag of an artist, so artists with several tags span several rows.
+/// Artists without any tags get a single row with no tag, so that they're
+/// still listed.
+#[derive(Serialize)]
+struct ArtistTagRow<'a> {
+    artist: &'a str,
+    genre: Option<&'a str>,
+    tag: Option<&'a str>,
+    weight: Option<u32>,
+}
+
+/// Writes each dataset to its own `{basename}-{dataset}.csv` file, one flat
+/// row per item. See the readme for the columns of each file.
+pub struct CsvSink {
+    names: FileNames,
+    encoding: Encoding,
+    written: Vec<WrittenFile>,
+}
+
+impl CsvSink {
+    pub fn new(names: FileNames, encoding: Encoding) -> Self {
+        CsvSink {
+            names,
+            encoding,
+            written: Vec::new(),
+        }
+    }
+
+    fn write<T: Serialize>(&mut self, dataset: &str, rows: &[T]) -> anyhow::Result<()> {
+        let filename = self.names.dataset(dataset) + ".csv";
+        serialize::write_csv(filename.clone(), rows, &self.encoding)?;
+        self.written.push(WrittenFile::dataset(
+            filename + &self.encoding.extension(),
+            FileFormat::Csv,
+            dataset,
+            rows.len(),
+        ));
+        Ok(())
+    }
+}
+
+impl BackupSink for CsvSink {
+    fn begin_run(&mut self, _username: &str) -> anyhow::Result<()> {
+        log::info!("Writing CSV...");
+        Ok(())
+    }
+
+    fn write_loved_tracks(&mut self, loved_tracks: Vec<LovedTrack>) -> anyhow::Result<()> {
+        let rows: Vec<LovedTrackRow> = loved_tracks.iter().map(LovedTrackRow::from).collect();
+        self.write("loved_tracks", &rows)
+    }
+
+    fn write_friends(&mut self, friends: Vec<Friend>) -> anyhow::Result<()> {
+        let rows: Vec<FriendRow> = friends.iter().map(FriendRow::from).collect();
+        self.write("friends", &rows)
+    }
+
+    fn write_friend_graph(&mut self, graph: FriendGraph) -> anyhow::Result<()> {
+        let users: Vec<UserRow> = graph.users.iter().map(UserRow::from).collect();
+        self.write("users", &users)?;
+        self.write("friendships", &graph.friendships)
+    }
+
+    fn write_scrobbles(&mut self, scrobbles: Vec<Track>) -> anyhow::Result<()> {
+        let rows: Vec<TrackRow> = scrobbles.iter().map(TrackRow::from).collect();
+        self.write("scrobbles", &rows)
+    }
+
+    fn write_now_playing(&mut self, now_playing: Vec<Track>) -> anyhow::Result<()> {
+        let rows: Vec<TrackRow> = now_playing.iter().map(TrackRow::from).collect();
+        self.write("now_playing", &rows)
+    }
+
+    fn write_artist_tags(&mut self, artist_tags: Vec<ArtistTags>) -> anyhow::Result<()> {
+        let rows: Vec<ArtistTagRow> = artist_tags
+            .iter()
+            .flat_map(|artist| {
+                let untagged = artist.tags.is_empty().then_some(None);
+                artist
+                    .tags
+                    .iter()
+                    .map(Some)
+                    .chain(untagged)
+                    .map(move |tag| ArtistTagRow {
+                        artist: &artist.artist,
+                        genre: artist.genre.as_deref(),
+                        tag: tag.map(|tag| tag.name.as_str()),
+                        weight: tag.map(|tag| tag.weight),
+                    })
+            })
+            .collect();
+        self.write("artist_tags", &rows)
+    }
+
+    fn finish(&mut self, _success: bool) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn written_files(&self) -> Vec<WrittenFile> {
+        self.written.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scrobble(name: &str, timestamp: i64) -> Track {
+        serde_json::from_value(serde_json::json!({
+            "artist": {"name": "Artist"},
+            "name": name,
+            "date": {"pretty_string": "", "timestamp": timestamp},
+            "url": ""
+        }))
+        .unwrap()
+    }
+
+    fn count_scrobbles(filename: &std::path::Path) -> i64 {
+        let conn = open_db_read_only(&filename.to_string_lossy()).unwrap();
+        conn.query_row("SELECT COUNT(*) FROM scrobbles", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn synced_runs_are_committed_only_once_finished() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("sync.db");
+
+        let mut sink = SqliteSink::sync(&filename);
+        sink.begin_run("someone").unwrap();
+        sink.write_scrobbles(vec![scrobble("A", 1)]).unwrap();
+        sink.finish(true).unwrap();
+        assert_eq!(count_scrobbles(&filename), 1);
+
+        // Dropped without finishing, as if the run had crashed
+        let mut sink = SqliteSink::sync(&filename);
+        sink.begin_run("someone").unwrap();
+        sink.write_scrobbles(vec![scrobble("A", 1), scrobble("B", 2)])
+            .unwrap();
+        drop(sink);
+        assert_eq!(count_scrobbles(&filename), 1);
+    }
+
+    #[test]
+    fn artists_without_tags_still_get_a_row() {
+        let dir = tempfile::tempdir().unwrap();
+        let names = FileNames::new(dir.path().join("hatchery").to_string_lossy());
+        let mut sink = CsvSink::new(names, Encoding::default());
+        sink.write_artist_tags(vec![
+            ArtistTags {
+                artist: "Tagged".to_string(),
+                genre: Some("rock".to_string()),
+                tags: vec![Tag {
+                    name: "rock".to_string(),
+                    weight: 100,
+                }],
+            },
+            ArtistTags {
+                artist: "Untagged".to_string(),
+                genre: None,
+                tags: Vec::new(),
+            },
+        ])
+        .unwrap();
+
+        let csv = fs::read_to_string(dir.path().join("hatchery-artist_tags.csv")).unwrap();
+        assert_eq!(
+            csv,
+            "artist,genre,tag,weight\nTagged,rock,rock,100\nUntagged,,,\n"
+        );
+    }
+}
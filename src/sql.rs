@@ -1,77 +1,835 @@
 use super::api::*;
-use rusqlite::{params, Connection};
+use super::encryption::Decryption;
+use super::graph::{FriendGraph, Friendship, User};
+use super::serialize::DecodedFile;
+use super::sink::Backup;
+use super::tags::ArtistTags;
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::str::FromStr;
 
 pub fn open_db(filename: &str) -> rusqlite::Result<Connection> {
     Connection::open(filename)
 }
 
-pub fn close_db(conn: Connection) -> rusqlite::Result<(), (Connection, rusqlite::Error)> {
-    conn.close()
+/// Opens an existing database without any way of changing it.
+pub fn open_db_read_only(filename: &str) -> rusqlite::Result<Connection> {
+    Connection::open_with_flags(
+        filename,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
 }
 
-pub fn create_tables(conn: &mut Connection) -> rusqlite::Result<()> {
-    conn.execute("DROP TABLE IF EXISTS scrobbles", [])?;
-    conn.execute(
-        "CREATE TABLE scrobbles (
-            id             INTEGER PRIMARY KEY,
-            name           TEXT NOT NULL,
-            mbid           TEXT,
-            artist         TEXT NOT NULL,
-            artist_mbid    TEXT,
-            album          TEXT NOT NULL,
-            album_mbid     TEXT,
-            timestamp      DATETIME
-        )",
+pub fn close_db(conn: Connection) -> rusqlite::Result<()> {
+    conn.close().map_err(|(_, e)| e)
+}
+
+/// Every change ever made to the schema, oldest first. A database's schema
+/// version is the number of these that have been applied to it, so new
+/// changes must only ever be appended.
+const MIGRATIONS: &[&str] = &[
+    // 1: Scrobbles, loved tracks and friends
+    "CREATE TABLE scrobbles (
+        id             INTEGER PRIMARY KEY,
+        name           TEXT NOT NULL,
+        mbid           TEXT,
+        artist         TEXT NOT NULL,
+        artist_mbid    TEXT,
+        album          TEXT NOT NULL,
+        album_mbid     TEXT,
+        timestamp      DATETIME
+    );
+    CREATE TABLE loved_tracks (
+        id             INTEGER PRIMARY KEY,
+        name           TEXT NOT NULL,
+        mbid           TEXT,
+        artist         TEXT NOT NULL,
+        artist_mbid    TEXT
+    );
+    CREATE TABLE friends (
+        id             INTEGER PRIMARY KEY,
+        name           TEXT NOT NULL,
+        real_name      TEXT,
+        country        TEXT NOT NULL,
+        subscriber     BOOLEAN,
+        registered     DATETIME
+    );",
+    // 2: Artist tags and genres, the friends graph and now playing, none of
+    // which the first released version wrote
+    "ALTER TABLE scrobbles ADD COLUMN genre TEXT;
+    ALTER TABLE loved_tracks ADD COLUMN genre TEXT;
+    CREATE TABLE artist_tags (
+        id             INTEGER PRIMARY KEY,
+        artist         TEXT NOT NULL,
+        tag            TEXT NOT NULL,
+        weight         INTEGER NOT NULL
+    );
+    CREATE TABLE users (
+        id             INTEGER PRIMARY KEY,
+        name           TEXT NOT NULL,
+        depth          INTEGER NOT NULL,
+        real_name      TEXT,
+        country        TEXT,
+        subscriber     BOOLEAN,
+        registered     DATETIME
+    );
+    CREATE TABLE friendships (
+        id             INTEGER PRIMARY KEY,
+        user           TEXT NOT NULL,
+        friend         TEXT NOT NULL
+    );
+    CREATE TABLE now_playing (
+        id             INTEGER PRIMARY KEY,
+        name           TEXT NOT NULL,
+        mbid           TEXT,
+        artist         TEXT NOT NULL,
+        artist_mbid    TEXT,
+        album          TEXT,
+        album_mbid     TEXT
+    );",
+    // 3: Runs, and unique keys so that data can be synced into the same
+    // database over and over
+    "CREATE TABLE runs (
+        id             INTEGER PRIMARY KEY,
+        username       TEXT NOT NULL,
+        started        DATETIME NOT NULL,
+        finished       DATETIME
+    );
+    ALTER TABLE scrobbles ADD COLUMN first_seen_run INTEGER REFERENCES runs (id);
+    ALTER TABLE scrobbles ADD COLUMN last_seen_run INTEGER REFERENCES runs (id);
+    ALTER TABLE loved_tracks ADD COLUMN first_seen_run INTEGER REFERENCES runs (id);
+    ALTER TABLE loved_tracks ADD COLUMN last_seen_run INTEGER REFERENCES runs (id);
+    ALTER TABLE friends ADD COLUMN first_seen_run INTEGER REFERENCES runs (id);
+    ALTER TABLE friends ADD COLUMN last_seen_run INTEGER REFERENCES runs (id);
+    ALTER TABLE users ADD COLUMN first_seen_run INTEGER REFERENCES runs (id);
+    ALTER TABLE users ADD COLUMN last_seen_run INTEGER REFERENCES runs (id);
+    ALTER TABLE friendships ADD COLUMN first_seen_run INTEGER REFERENCES runs (id);
+    ALTER TABLE friendships ADD COLUMN last_seen_run INTEGER REFERENCES runs (id);
+    ALTER TABLE now_playing ADD COLUMN run INTEGER REFERENCES runs (id);
+    DELETE FROM scrobbles WHERE id NOT IN
+        (SELECT MIN(id) FROM scrobbles GROUP BY timestamp, artist, name);
+    CREATE UNIQUE INDEX scrobbles_key ON scrobbles (timestamp, artist, name);
+    DELETE FROM loved_tracks WHERE id NOT IN
+        (SELECT MIN(id) FROM loved_tracks GROUP BY artist, name);
+    CREATE UNIQUE INDEX loved_tracks_key ON loved_tracks (artist, name);
+    DELETE FROM friends WHERE id NOT IN
+        (SELECT MIN(id) FROM friends GROUP BY name);
+    CREATE UNIQUE INDEX friends_key ON friends (name);
+    DELETE FROM artist_tags WHERE id NOT IN
+        (SELECT MIN(id) FROM artist_tags GROUP BY artist, tag);
+    CREATE UNIQUE INDEX artist_tags_key ON artist_tags (artist, tag);
+    DELETE FROM users WHERE id NOT IN
+        (SELECT MIN(id) FROM users GROUP BY name);
+    CREATE UNIQUE INDEX users_key ON users (name);
+    DELETE FROM friendships WHERE id NOT IN
+        (SELECT MIN(id) FROM friendships GROUP BY user, friend);
+    CREATE UNIQUE INDEX friendships_key ON friendships (user, friend);",
+    // 4: Scrobble edits and deletions
+    "ALTER TABLE scrobbles ADD COLUMN deleted_run INTEGER REFERENCES runs (id);
+    CREATE TABLE scrobble_changes (
+        id             INTEGER PRIMARY KEY,
+        run            INTEGER NOT NULL REFERENCES runs (id),
+        kind           TEXT NOT NULL,
+        timestamp      DATETIME NOT NULL,
+        old_name       TEXT NOT NULL,
+        old_artist     TEXT NOT NULL,
+        old_album      TEXT,
+        new_name       TEXT,
+        new_artist     TEXT,
+        new_album      TEXT
+    );",
+    // 5: Artists, albums and tracks get their own tables, referenced by
+    // scrobbles and loved tracks. The old flat tables live on as views.
+    "CREATE TABLE artists (
+        id             INTEGER PRIMARY KEY,
+        name           TEXT NOT NULL,
+        mbid           TEXT,
+        genre          TEXT
+    );
+    CREATE UNIQUE INDEX artists_key ON artists (name);
+    CREATE TABLE albums (
+        id             INTEGER PRIMARY KEY,
+        artist_id      INTEGER NOT NULL REFERENCES artists (id),
+        name           TEXT NOT NULL,
+        mbid           TEXT
+    );
+    CREATE UNIQUE INDEX albums_key ON albums (artist_id, name);
+    CREATE TABLE tracks (
+        id             INTEGER PRIMARY KEY,
+        artist_id      INTEGER NOT NULL REFERENCES artists (id),
+        name           TEXT NOT NULL,
+        mbid           TEXT
+    );
+    CREATE UNIQUE INDEX tracks_key ON tracks (artist_id, name);
+
+    INSERT OR IGNORE INTO artists (name, mbid, genre)
+        SELECT artist, artist_mbid, genre FROM scrobbles;
+    INSERT OR IGNORE INTO artists (name, mbid, genre)
+        SELECT artist, artist_mbid, genre FROM loved_tracks;
+    INSERT OR IGNORE INTO albums (artist_id, name, mbid)
+        SELECT artists.id, scrobbles.album, scrobbles.album_mbid FROM scrobbles
+        JOIN artists ON artists.name = scrobbles.artist
+        WHERE scrobbles.album IS NOT NULL;
+    INSERT OR IGNORE INTO tracks (artist_id, name, mbid)
+        SELECT artists.id, scrobbles.name, scrobbles.mbid FROM scrobbles
+        JOIN artists ON artists.name = scrobbles.artist;
+    INSERT OR IGNORE INTO tracks (artist_id, name, mbid)
+        SELECT artists.id, loved_tracks.name, loved_tracks.mbid FROM loved_tracks
+        JOIN artists ON artists.name = loved_tracks.artist;
+
+    CREATE TABLE scrobbles_normalized (
+        id             INTEGER PRIMARY KEY,
+        track_id       INTEGER NOT NULL REFERENCES tracks (id),
+        album_id       INTEGER REFERENCES albums (id),
+        timestamp      DATETIME,
+        first_seen_run INTEGER REFERENCES runs (id),
+        last_seen_run  INTEGER REFERENCES runs (id),
+        deleted_run    INTEGER REFERENCES runs (id)
+    );
+    INSERT INTO scrobbles_normalized
+        SELECT scrobbles.id, tracks.id, albums.id, scrobbles.timestamp,
+            scrobbles.first_seen_run, scrobbles.last_seen_run, scrobbles.deleted_run
+        FROM scrobbles
+        JOIN artists ON artists.name = scrobbles.artist
+        JOIN tracks ON tracks.artist_id = artists.id AND tracks.name = scrobbles.name
+        LEFT JOIN albums ON albums.artist_id = artists.id AND albums.name = scrobbles.album;
+    DROP TABLE scrobbles;
+    ALTER TABLE scrobbles_normalized RENAME TO scrobbles;
+    CREATE UNIQUE INDEX scrobbles_key ON scrobbles (timestamp, track_id);
+
+    CREATE TABLE loved_tracks_normalized (
+        id             INTEGER PRIMARY KEY,
+        track_id       INTEGER NOT NULL REFERENCES tracks (id),
+        first_seen_run INTEGER REFERENCES runs (id),
+        last_seen_run  INTEGER REFERENCES runs (id)
+    );
+    INSERT INTO loved_tracks_normalized
+        SELECT loved_tracks.id, tracks.id,
+            loved_tracks.first_seen_run, loved_tracks.last_seen_run
+        FROM loved_tracks
+        JOIN artists ON artists.name = loved_tracks.artist
+        JOIN tracks ON tracks.artist_id = artists.id AND tracks.name = loved_tracks.name;
+    DROP TABLE loved_tracks;
+    ALTER TABLE loved_tracks_normalized RENAME TO loved_tracks;
+    CREATE UNIQUE INDEX loved_tracks_key ON loved_tracks (track_id);
+
+    CREATE VIEW scrobbles_flat AS
+        SELECT scrobbles.id, tracks.name, tracks.mbid,
+            artists.name AS artist, artists.mbid AS artist_mbid,
+            albums.name AS album, albums.mbid AS album_mbid,
+            scrobbles.timestamp, artists.genre,
+            scrobbles.first_seen_run, scrobbles.last_seen_run, scrobbles.deleted_run
+        FROM scrobbles
+        JOIN tracks ON tracks.id = scrobbles.track_id
+        JOIN artists ON artists.id = tracks.artist_id
+        LEFT JOIN albums ON albums.id = scrobbles.album_id;
+    CREATE VIEW loved_tracks_flat AS
+        SELECT loved_tracks.id, tracks.name, tracks.mbid,
+            artists.name AS artist, artists.mbid AS artist_mbid, artists.genre,
+            loved_tracks.first_seen_run, loved_tracks.last_seen_run
+        FROM loved_tracks
+        JOIN tracks ON tracks.id = loved_tracks.track_id
+        JOIN artists ON artists.id = tracks.artist_id;",
+    // 6: Everything else the JSON export keeps, so nothing is lost
+    "ALTER TABLE artists ADD COLUMN url TEXT;
+    ALTER TABLE tracks ADD COLUMN url TEXT;
+    ALTER TABLE scrobbles ADD COLUMN date_text TEXT;
+    ALTER TABLE scrobbles ADD COLUMN images TEXT;
+    ALTER TABLE loved_tracks ADD COLUMN date DATETIME;
+    ALTER TABLE loved_tracks ADD COLUMN date_text TEXT;
+    ALTER TABLE loved_tracks ADD COLUMN images TEXT;
+    ALTER TABLE now_playing ADD COLUMN url TEXT;
+    ALTER TABLE now_playing ADD COLUMN images TEXT;
+    ALTER TABLE friends ADD COLUMN url TEXT;
+    ALTER TABLE friends ADD COLUMN images TEXT;
+    ALTER TABLE friends ADD COLUMN registered_text TEXT;
+    ALTER TABLE users ADD COLUMN url TEXT;
+    ALTER TABLE users ADD COLUMN images TEXT;
+    ALTER TABLE users ADD COLUMN registered_text TEXT;",
+    // 7: The MBIDs and URLs of each scrobble and loved track as Last.fm sent
+    // them, as artists, albums and tracks only keep one of each. Rows from
+    // before have no URL and fall back to those
+    "ALTER TABLE scrobbles ADD COLUMN mbid TEXT;
+    ALTER TABLE scrobbles ADD COLUMN url TEXT;
+    ALTER TABLE scrobbles ADD COLUMN artist_mbid TEXT;
+    ALTER TABLE scrobbles ADD COLUMN album_mbid TEXT;
+    ALTER TABLE loved_tracks ADD COLUMN mbid TEXT;
+    ALTER TABLE loved_tracks ADD COLUMN url TEXT;
+    ALTER TABLE loved_tracks ADD COLUMN artist_mbid TEXT;
+    ALTER TABLE loved_tracks ADD COLUMN artist_url TEXT;
+
+    DROP VIEW scrobbles_flat;
+    CREATE VIEW scrobbles_flat AS
+        SELECT scrobbles.id, tracks.name,
+            CASE WHEN scrobbles.url IS NULL THEN tracks.mbid ELSE scrobbles.mbid END AS mbid,
+            artists.name AS artist,
+            CASE WHEN scrobbles.url IS NULL THEN artists.mbid ELSE scrobbles.artist_mbid END
+                AS artist_mbid,
+            albums.name AS album,
+            CASE WHEN scrobbles.url IS NULL THEN albums.mbid ELSE scrobbles.album_mbid END
+                AS album_mbid,
+            scrobbles.timestamp, artists.genre,
+            scrobbles.first_seen_run, scrobbles.last_seen_run, scrobbles.deleted_run
+        FROM scrobbles
+        JOIN tracks ON tracks.id = scrobbles.track_id
+        JOIN artists ON artists.id = tracks.artist_id
+        LEFT JOIN albums ON albums.id = scrobbles.album_id;
+    DROP VIEW loved_tracks_flat;
+    CREATE VIEW loved_tracks_flat AS
+        SELECT loved_tracks.id, tracks.name,
+            CASE WHEN loved_tracks.url IS NULL THEN tracks.mbid ELSE loved_tracks.mbid END
+                AS mbid,
+            artists.name AS artist,
+            CASE WHEN loved_tracks.url IS NULL THEN artists.mbid ELSE loved_tracks.artist_mbid END
+                AS artist_mbid,
+            artists.genre,
+            loved_tracks.first_seen_run, loved_tracks.last_seen_run
+        FROM loved_tracks
+        JOIN tracks ON tracks.id = loved_tracks.track_id
+        JOIN artists ON artists.id = tracks.artist_id;",
+    // 8: Every artist whose tags were looked up, so that artists without
+    // any are kept too. Only artists with tags were kept before
+    "CREATE TABLE tagged_artists (
+        id             INTEGER PRIMARY KEY,
+        artist         TEXT NOT NULL
+    );
+    CREATE UNIQUE INDEX tagged_artists_key ON tagged_artists (artist);
+    INSERT INTO tagged_artists (artist)
+        SELECT artist FROM artist_tags GROUP BY artist ORDER BY MIN(id);",
+];
+
+pub fn table_exists(conn: &Connection, table: &str) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+        params![table],
+        |row| row.get(0),
+    )
+}
+
+/// Works out the version of a database written before `schema_version`
+/// existed, which only the first released version did, with the tables of
+/// migration 1.
+fn legacy_schema_version(conn: &Connection) -> rusqlite::Result<usize> {
+    Ok(if table_exists(conn, "scrobbles")? {
+        1
+    } else {
+        0
+    })
+}
+
+pub fn schema_version(conn: &Connection) -> rusqlite::Result<usize> {
+    if table_exists(conn, "schema_version")? {
+        conn.query_row("SELECT version FROM schema_version", [], |row| row.get(0))
+    } else {
+        legacy_schema_version(conn)
+    }
+}
+
+/// Brings the database up to the latest schema, creating it if it's empty.
+/// Existing data is left untouched.
+pub fn migrate(conn: &mut Connection) -> anyhow::Result<()> {
+    let version = schema_version(conn)?;
+    if version > MIGRATIONS.len() {
+        return Err(anyhow!(
+            "Database schema version {} is newer than this version of hatchery supports ({})",
+            version,
+            MIGRATIONS.len()
+        ));
+    }
+
+    let trans = conn.transaction()?;
+    trans.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)",
         [],
     )?;
-    conn.execute("DROP TABLE IF EXISTS loved_tracks", [])?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        log::debug!("Migrating database to schema version {}...", i + 1);
+        trans.execute_batch(migration)?;
+    }
+    trans.execute("DELETE FROM schema_version", [])?;
+    trans.execute(
+        "INSERT INTO schema_version (version) VALUES (?1)",
+        params![MIGRATIONS.len()],
+    )?;
+    trans.commit()?;
+    Ok(())
+}
+
+/// Records the start of a run, returning its ID. A database only ever holds
+/// a single user's data, so runs for anyone else are refused.
+pub fn begin_run(conn: &mut Connection, username: &str) -> anyhow::Result<i64> {
+    let other_user: Option<String> = conn
+        .query_row(
+            "SELECT username FROM runs WHERE username != ?1 COLLATE NOCASE LIMIT 1",
+            params![username],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if let Some(other_user) = other_user {
+        return Err(anyhow!(
+            "Database already holds {}'s data, refusing to add {}'s",
+            other_user,
+            username
+        ));
+    }
+
     conn.execute(
-        "CREATE TABLE loved_tracks (
-            id             INTEGER PRIMARY KEY,
-            name           TEXT NOT NULL,
-            mbid           TEXT,
-            artist         TEXT NOT NULL,
-            artist_mbid    TEXT
-        )",
-        [],
+        "INSERT INTO runs (username, started) VALUES (?1, ?2)",
+        params![username, Utc::now()],
     )?;
-    conn.execute("DROP TABLE IF EXISTS friends", [])?;
+    Ok(conn.last_insert_rowid())
+}
+
+pub fn finish_run(conn: &mut Connection, run: i64) -> rusqlite::Result<()> {
     conn.execute(
-        "CREATE TABLE friends (
-            id             INTEGER PRIMARY KEY,
-            name           TEXT NOT NULL,
-            real_name      TEXT,
-            country        TEXT NOT NULL,
-            subscriber     BOOLEAN,
-            registered     DATETIME
-        )",
-        [],
+        "UPDATE runs SET finished = ?1 WHERE id = ?2",
+        params![Utc::now(), run],
     )?;
     Ok(())
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Edited,
+    Deleted,
+}
+
+impl ChangeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeKind::Edited => "edited",
+            ChangeKind::Deleted => "deleted",
+        }
+    }
+}
+
+impl FromStr for ChangeKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "edited" => Ok(ChangeKind::Edited),
+            "deleted" => Ok(ChangeKind::Deleted),
+            _ => Err(anyhow!("Unknown change kind {}", s)),
+        }
+    }
+}
+
+/// The name, artist and album of a scrobble.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScrobbleInfo {
+    pub name: String,
+    pub artist: String,
+    pub album: Option<String>,
+}
+
+/// A scrobble that was edited or deleted on Last.fm after being backed up.
+#[derive(Debug)]
+pub struct ScrobbleChange {
+    pub run: i64,
+    pub run_started: DateTime<Utc>,
+    pub kind: ChangeKind,
+    pub timestamp: DateTime<Utc>,
+    pub old: ScrobbleInfo,
+    /// What the scrobble was edited into. Not set for deletions.
+    pub new: Option<ScrobbleInfo>,
+}
+
+/// A stored scrobble that no longer matches what Last.fm returned.
+#[derive(Debug)]
+pub struct DetectedChange<'a> {
+    /// ID of the stored scrobble
+    pub id: i64,
+    pub timestamp: DateTime<Utc>,
+    pub kind: ChangeKind,
+    pub old: &'a ScrobbleInfo,
+    pub new: Option<&'a ScrobbleInfo>,
+    /// Whether the stored scrobble is gone, rather than just its album
+    /// having changed, and should be marked as deleted
+    pub missing: bool,
+}
+
+/// The timestamp, name, artist and album of every fetched scrobble.
+pub fn fetched_scrobble_infos(scrobbles: &[Track]) -> Vec<(DateTime<Utc>, ScrobbleInfo)> {
+    scrobbles
+        .iter()
+        .filter_map(|track| {
+            track.date.as_ref().map(|date| {
+                (
+                    date.datetime,
+                    ScrobbleInfo {
+                        name: track.name.clone(),
+                        artist: track.artist.name.clone(),
+                        album: track.album.as_ref().map(|album| album.name.clone()),
+                    },
+                )
+            })
+        })
+        .collect()
+}
+
+/// Compares `stored` scrobbles (ID, timestamp and info) against `fetched`
+/// ones, pairing every stored scrobble that went missing with an unstored
+/// fetched scrobble at the same timestamp where possible.
+pub fn diff_scrobbles<'a>(
+    stored: &'a [(i64, DateTime<Utc>, ScrobbleInfo)],
+    fetched: &'a [(DateTime<Utc>, ScrobbleInfo)],
+) -> Vec<DetectedChange<'a>> {
+    let stored_keys: HashSet<(DateTime<Utc>, &str, &str)> = stored
+        .iter()
+        .map(|(_, timestamp, info)| (*timestamp, info.artist.as_str(), info.name.as_str()))
+        .collect();
+    let mut fetched_by_key: HashMap<(DateTime<Utc>, &str, &str), &ScrobbleInfo> = HashMap::new();
+    // Fetched scrobbles that aren't stored yet are candidates for what
+    // a missing scrobble with the same timestamp was edited into
+    let mut unmatched: HashMap<DateTime<Utc>, Vec<&ScrobbleInfo>> = HashMap::new();
+    for (timestamp, info) in fetched {
+        let key = (*timestamp, info.artist.as_str(), info.name.as_str());
+        fetched_by_key.insert(key, info);
+        if !stored_keys.contains(&key) {
+            unmatched.entry(*timestamp).or_default().push(info);
+        }
+    }
+
+    let mut changes = Vec::new();
+    for (id, timestamp, old) in stored {
+        let (kind, new, missing) =
+            match fetched_by_key.get(&(*timestamp, old.artist.as_str(), old.name.as_str())) {
+                // Still there, but the album may have been edited
+                Some(new) if new.album == old.album => continue,
+                Some(new) => (ChangeKind::Edited, Some(*new), false),
+                None => match unmatched.get_mut(timestamp).and_then(|infos| infos.pop()) {
+                    Some(new) => (ChangeKind::Edited, Some(new), true),
+                    None => (ChangeKind::Deleted, None, true),
+                },
+            };
+        changes.push(DetectedChange {
+            id: *id,
+            timestamp: *timestamp,
+            kind,
+            old,
+            new,
+            missing,
+        });
+    }
+    changes
+}
+
+/// Compares freshly fetched scrobbles against those already in the
+/// database, recording every stored scrobble that was edited or deleted
+/// since in `scrobble_changes` and marking it as deleted by `run`.
+///
+/// When the scrobbles are `complete`, every stored scrobble is compared, so
+/// deleting even the oldest is noticed. Otherwise missing scrobbles may only
+/// have been skipped, so only stored scrobbles at least as recent as the
+/// oldest fetched one are compared and nothing counts as deleted. Returns
+/// the number of changes found.
+pub fn record_scrobble_changes(
+    conn: &mut Connection,
+    run: i64,
+    scrobbles: &[Track],
+    complete: bool,
+) -> Result<usize, rusqlite::Error> {
+    let fetched = fetched_scrobble_infos(scrobbles);
+    let oldest = match fetched.iter().map(|(timestamp, _)| *timestamp).min() {
+        Some(oldest) => oldest,
+        None => return Ok(0),
+    };
+
+    let trans = conn.savepoint()?;
+    let changes;
+
+    {
+        let mut statement = trans.prepare(
+            "SELECT id, timestamp, name, artist, album FROM scrobbles_flat
+                WHERE deleted_run IS NULL AND (?1 IS NULL OR timestamp >= ?1)
+            ",
+        )?;
+        let since = if complete { None } else { Some(oldest) };
+        let stored = statement
+            .query_map(params![since], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, DateTime<Utc>>(1)?,
+                    ScrobbleInfo {
+                        name: row.get(2)?,
+                        artist: row.get(3)?,
+                        album: row.get(4)?,
+                    },
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut insert_change = trans.prepare(
+            "INSERT INTO scrobble_changes
+                (run, kind, timestamp, old_name, old_artist, old_album,
+                 new_name, new_artist, new_album)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+            ",
+        )?;
+        let mut mark_deleted =
+            trans.prepare("UPDATE scrobbles SET deleted_run = ?1 WHERE id = ?2")?;
+
+        let mut detected = diff_scrobbles(&stored, &fetched);
+        if !complete {
+            detected.retain(|change| change.kind != ChangeKind::Deleted);
+        }
+        for change in &detected {
+            if change.missing {
+                mark_deleted.execute(params![run, change.id])?;
+            }
+            insert_change.execute(params![
+                run,
+                change.kind.as_str(),
+                change.timestamp,
+                change.old.name,
+                change.old.artist,
+                change.old.album,
+                change.new.map(|new| &new.name),
+                change.new.map(|new| &new.artist),
+                change.new.and_then(|new| new.album.as_ref()),
+            ])?;
+        }
+        changes = detected.len();
+    }
+    trans.commit()?;
+    Ok(changes)
+}
+
+/// Reads every recorded scrobble change, oldest first.
+pub fn read_scrobble_changes(conn: &Connection) -> anyhow::Result<Vec<ScrobbleChange>> {
+    let mut statement = conn.prepare(
+        "SELECT scrobble_changes.run, runs.started, kind, timestamp,
+                old_name, old_artist, old_album, new_name, new_artist, new_album
+            FROM scrobble_changes
+            JOIN runs ON runs.id = scrobble_changes.run
+            ORDER BY scrobble_changes.id
+        ",
+    )?;
+    let mut rows = statement.query([])?;
+
+    let mut changes: Vec<ScrobbleChange> = Vec::new();
+    while let Some(row) = rows.next()? {
+        let new_name: Option<String> = row.get(7)?;
+        let new_artist: Option<String> = row.get(8)?;
+        changes.push(ScrobbleChange {
+            run: row.get(0)?,
+            run_started: row.get(1)?,
+            kind: row.get::<_, String>(2)?.parse()?,
+            timestamp: row.get(3)?,
+            old: ScrobbleInfo {
+                name: row.get(4)?,
+                artist: row.get(5)?,
+                album: row.get(6)?,
+            },
+            new: match (new_name, new_artist) {
+                (Some(name), Some(artist)) => Some(ScrobbleInfo {
+                    name,
+                    artist,
+                    album: row.get(9)?,
+                }),
+                _ => None,
+            },
+        });
+    }
+    Ok(changes)
+}
+
+/// How images are stored: a JSON array in a single column, in the order
+/// Last.fm returned them.
+#[derive(Deserialize, Serialize)]
+struct StoredImage {
+    size: ImageSize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+}
+
+pub(crate) fn images_to_json(images: &[Image]) -> String {
+    let images: Vec<StoredImage> = images
+        .iter()
+        .map(|image| StoredImage {
+            size: image.size.clone(),
+            url: image.url.clone(),
+        })
+        .collect();
+    serde_json::to_string(&images).expect("images are always serializable")
+}
+
+fn images_from_json(json: Option<String>) -> rusqlite::Result<Vec<Image>> {
+    let images: Vec<StoredImage> = match json {
+        Some(json) => serde_json::from_str(&json)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(e)))?,
+        None => Vec::new(),
+    };
+    Ok(images
+        .into_iter()
+        .map(|image| Image {
+            url: image.url,
+            size: image.size,
+        })
+        .collect())
+}
+
+/// Rows written before the original date text was stored get it rebuilt in
+/// the format Last.fm uses.
+fn date_text_or_default(text: Option<String>, datetime: &DateTime<Utc>) -> String {
+    text.unwrap_or_else(|| datetime.format("%d %b %Y, %H:%M").to_string())
+}
+
+fn upsert_artist(
+    trans: &Connection,
+    name: &str,
+    mbid: Option<&str>,
+    url: Option<&str>,
+) -> rusqlite::Result<i64> {
+    // Only loved tracks come with an artist URL, and not every scrobble comes
+    // with an MBID, so neither should be erased once known
+    trans
+        .prepare_cached(
+            "INSERT INTO artists (name, mbid, url) VALUES (?1, ?2, ?3)
+                ON CONFLICT (name) DO UPDATE SET
+                    mbid = COALESCE(excluded.mbid, mbid),
+                    url = COALESCE(excluded.url, url)
+            ",
+        )?
+        .execute(params![name, mbid, url])?;
+    trans
+        .prepare_cached("SELECT id FROM artists WHERE name = ?1")?
+        .query_row(params![name], |row| row.get(0))
+}
+
+fn upsert_album(
+    trans: &Connection,
+    artist_id: i64,
+    name: &str,
+    mbid: Option<&str>,
+) -> rusqlite::Result<i64> {
+    trans
+        .prepare_cached(
+            "INSERT INTO albums (artist_id, name, mbid) VALUES (?1, ?2, ?3)
+                ON CONFLICT (artist_id, name) DO UPDATE SET
+                    mbid = COALESCE(excluded.mbid, mbid)
+            ",
+        )?
+        .execute(params![artist_id, name, mbid])?;
+    trans
+        .prepare_cached("SELECT id FROM albums WHERE artist_id = ?1 AND name = ?2")?
+        .query_row(params![artist_id, name], |row| row.get(0))
+}
+
+fn upsert_track(
+    trans: &Connection,
+    artist_id: i64,
+    name: &str,
+    mbid: Option<&str>,
+    url: &str,
+) -> rusqlite::Result<i64> {
+    trans
+        .prepare_cached(
+            "INSERT INTO tracks (artist_id, name, mbid, url) VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT (artist_id, name) DO UPDATE SET
+                    mbid = COALESCE(excluded.mbid, mbid),
+                    url = excluded.url
+            ",
+        )?
+        .execute(params![artist_id, name, mbid, url])?;
+    trans
+        .prepare_cached("SELECT id FROM tracks WHERE artist_id = ?1 AND name = ?2")?
+        .query_row(params![artist_id, name], |row| row.get(0))
+}
+
+/// Inserts scrobbles that haven't been seen before, keyed on their
+/// timestamp, artist and name, and marks the rest as seen by `run`.
 pub fn insert_scrobbles(
     conn: &mut Connection,
+    run: i64,
     scrobbles: Vec<Track>,
 ) -> Result<(), rusqlite::Error> {
-    let trans = conn.transaction()?;
+    let trans = conn.savepoint()?;
 
     {
         let mut statement = trans.prepare(
             "INSERT INTO scrobbles
-                (name, mbid, artist, artist_mbid, album, album_mbid, timestamp)
-                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                (track_id, album_id, timestamp, date_text, images,
+                 first_seen_run, last_seen_run, mbid, url, artist_mbid, album_mbid)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6, ?7, ?8, ?9, ?10)
+                ON CONFLICT (timestamp, track_id) DO UPDATE SET
+                    album_id = excluded.album_id,
+                    date_text = excluded.date_text,
+                    images = excluded.images,
+                    mbid = excluded.mbid,
+                    url = excluded.url,
+                    artist_mbid = excluded.artist_mbid,
+                    album_mbid = excluded.album_mbid,
+                    last_seen_run = excluded.last_seen_run,
+                    deleted_run = NULL
             ",
         )?;
 
         for track in scrobbles {
-            // Skip in-progress/unfinished scrobble
-            if let Some(attributes) = track.attributes {
-                if attributes.now_playing {
-                    continue;
-                }
-            }
+            let artist_id = upsert_artist(
+                &trans,
+                &track.artist.name,
+                track.artist.mbid.as_deref(),
+                None,
+            )?;
+            let track_id = upsert_track(
+                &trans,
+                artist_id,
+                &track.name,
+                track.mbid.as_deref(),
+                &track.url,
+            )?;
+            let album_id = match &track.album {
+                Some(album) => Some(upsert_album(
+                    &trans,
+                    artist_id,
+                    &album.name,
+                    album.mbid.as_deref(),
+                )?),
+                None => None,
+            };
 
+            statement.execute(params![
+                track_id,
+                album_id,
+                track.date.as_ref().map(|date| date.datetime),
+                track.date.map(|date| date.pretty_string),
+                images_to_json(&track.image),
+                run,
+                track.mbid,
+                track.url,
+                track.artist.mbid,
+                track.album.and_then(|album| album.mbid)
+            ])?;
+        }
+    }
+    trans.commit()
+}
+
+/// Inserts tracks that were still being listened to when the backup was
+/// made. These are kept apart from scrobbles as they have no timestamp yet.
+pub fn insert_now_playing(
+    conn: &mut Connection,
+    run: i64,
+    now_playing: Vec<Track>,
+) -> Result<(), rusqlite::Error> {
+    let trans = conn.savepoint()?;
+
+    {
+        let mut statement = trans.prepare(
+            "INSERT INTO now_playing
+                (name, mbid, artist, artist_mbid, album, album_mbid, url, images, run)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+            ",
+        )?;
+
+        for track in now_playing {
             // Get album info if exists
             let mut album_name: Option<String> = None;
             let mut album_mbid: Option<String> = None;
@@ -87,10 +845,9 @@ pub fn insert_scrobbles(
                 track.artist.mbid,
                 album_name,
                 album_mbid,
-                match track.date {
-                    Some(date) => Some(date.datetime),
-                    None => None,
-                }
+                track.url,
+                images_to_json(&track.image),
+                run
             ])?;
         }
     }
@@ -99,45 +856,82 @@ pub fn insert_scrobbles(
 
 pub fn insert_loved_tracks(
     conn: &mut Connection,
+    run: i64,
     loved_tracks: Vec<LovedTrack>,
 ) -> Result<(), rusqlite::Error> {
-    let trans = conn.transaction()?;
+    let trans = conn.savepoint()?;
 
     {
         let mut statement = trans.prepare(
             "INSERT INTO loved_tracks
-                (name, mbid, artist, artist_mbid)
-                VALUES (?1, ?2, ?3, ?4)
+                (track_id, date, date_text, images, first_seen_run, last_seen_run,
+                 mbid, url, artist_mbid, artist_url)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?5, ?6, ?7, ?8, ?9)
+                ON CONFLICT (track_id) DO UPDATE SET
+                    date = excluded.date,
+                    date_text = excluded.date_text,
+                    images = excluded.images,
+                    mbid = excluded.mbid,
+                    url = excluded.url,
+                    artist_mbid = excluded.artist_mbid,
+                    artist_url = excluded.artist_url,
+                    last_seen_run = excluded.last_seen_run
             ",
         )?;
 
         for track in loved_tracks {
-            // Skip in-progress/unfinished scrobble
-            if let Some(attributes) = track.attributes {
-                if attributes.now_playing {
-                    continue;
-                }
-            }
+            let artist_id = upsert_artist(
+                &trans,
+                &track.artist.name,
+                track.artist.mbid.as_deref(),
+                track.artist.url.as_deref(),
+            )?;
+            let track_id = upsert_track(
+                &trans,
+                artist_id,
+                &track.name,
+                track.mbid.as_deref(),
+                &track.url,
+            )?;
 
             statement.execute(params![
-                track.name,
+                track_id,
+                track.date.as_ref().map(|date| date.datetime),
+                track.date.map(|date| date.pretty_string),
+                images_to_json(&track.image),
+                run,
                 track.mbid,
-                track.artist.name,
+                track.url,
                 track.artist.mbid,
+                track.artist.url
             ])?;
         }
     }
     trans.commit()
 }
 
-pub fn insert_friends(conn: &mut Connection, friends: Vec<Friend>) -> Result<(), rusqlite::Error> {
-    let trans = conn.transaction()?;
+pub fn insert_friends(
+    conn: &mut Connection,
+    run: i64,
+    friends: Vec<Friend>,
+) -> Result<(), rusqlite::Error> {
+    let trans = conn.savepoint()?;
 
     {
         let mut statement = trans.prepare(
             "INSERT INTO friends
-                (name, real_name, country, subscriber, registered)
-                VALUES (?1, ?2, ?3, ?4, ?5)
+                (name, real_name, country, subscriber, registered, registered_text, url, images,
+                 first_seen_run, last_seen_run)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?9)
+                ON CONFLICT (name) DO UPDATE SET
+                    real_name = excluded.real_name,
+                    country = excluded.country,
+                    subscriber = excluded.subscriber,
+                    registered = excluded.registered,
+                    registered_text = excluded.registered_text,
+                    url = excluded.url,
+                    images = excluded.images,
+                    last_seen_run = excluded.last_seen_run
             ",
         )?;
 
@@ -145,11 +939,716 @@ pub fn insert_friends(conn: &mut Connection, friends: Vec<Friend>) -> Result<(),
             statement.execute(params![
                 friend.name,
                 friend.real_name,
-                friend.country,
+                friend.country.as_deref().unwrap_or_default(),
                 friend.subscriber,
-                friend.registered.datetime
+                friend.registered.datetime,
+                friend.registered.pretty_string,
+                friend.url,
+                images_to_json(&friend.image),
+                run
             ])?;
         }
     }
     trans.commit()
 }
+
+/// Inserts every artist's tags, including artists without any, and fills in
+/// the `genre` column of the artists already in the database.
+pub fn insert_artist_tags(
+    conn: &mut Connection,
+    artist_tags: Vec<ArtistTags>,
+) -> Result<(), rusqlite::Error> {
+    let trans = conn.savepoint()?;
+
+    {
+        let mut statement = trans.prepare(
+            "INSERT INTO artist_tags
+                (artist, tag, weight)
+                VALUES (?1, ?2, ?3)
+                ON CONFLICT (artist, tag) DO UPDATE SET
+                    weight = excluded.weight
+            ",
+        )?;
+        let mut tagged = trans.prepare(
+            "INSERT INTO tagged_artists (artist) VALUES (?1) ON CONFLICT (artist) DO NOTHING",
+        )?;
+        let mut update_genre =
+            trans.prepare("UPDATE artists SET genre = ?1 WHERE name = ?2 COLLATE NOCASE")?;
+
+        for artist in artist_tags {
+            tagged.execute(params![artist.artist])?;
+            for tag in artist.tags {
+                statement.execute(params![artist.artist, tag.name, tag.weight])?;
+            }
+
+            if let Some(genre) = artist.genre {
+                update_genre.execute(params![genre, artist.artist])?;
+            }
+        }
+    }
+    trans.commit()
+}
+
+pub fn insert_friend_graph(
+    conn: &mut Connection,
+    run: i64,
+    graph: FriendGraph,
+) -> Result<(), rusqlite::Error> {
+    let trans = conn.savepoint()?;
+
+    {
+        let mut statement = trans.prepare(
+            "INSERT INTO users
+                (name, depth, real_name, country, subscriber, registered, registered_text,
+                 url, images, first_seen_run, last_seen_run)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?10)
+                ON CONFLICT (name) DO UPDATE SET
+                    depth = MIN(depth, excluded.depth),
+                    real_name = COALESCE(excluded.real_name, real_name),
+                    country = COALESCE(excluded.country, country),
+                    subscriber = COALESCE(excluded.subscriber, subscriber),
+                    registered = COALESCE(excluded.registered, registered),
+                    registered_text = COALESCE(excluded.registered_text, registered_text),
+                    url = COALESCE(excluded.url, url),
+                    images = COALESCE(excluded.images, images),
+                    last_seen_run = excluded.last_seen_run
+            ",
+        )?;
+
+        for user in graph.users {
+            match user.profile {
+                Some(friend) => statement.execute(params![
+                    user.name,
+                    user.depth,
+                    friend.real_name,
+                    friend.country,
+                    friend.subscriber,
+                    friend.registered.datetime,
+                    friend.registered.pretty_string,
+                    friend.url,
+                    images_to_json(&friend.image),
+                    run
+                ])?,
+                None => statement.execute(params![
+                    user.name,
+                    user.depth,
+                    None::<String>,
+                    None::<String>,
+                    None::<bool>,
+                    None::<String>,
+                    None::<String>,
+                    None::<String>,
+                    None::<String>,
+                    run
+                ])?,
+            };
+        }
+
+        let mut statement = trans.prepare(
+            "INSERT INTO friendships
+                (user, friend, first_seen_run, last_seen_run)
+                VALUES (?1, ?2, ?3, ?3)
+                ON CONFLICT (user, friend) DO UPDATE SET
+                    last_seen_run = excluded.last_seen_run
+            ",
+        )?;
+
+        for friendship in graph.friendships {
+            statement.execute(params![friendship.user, friendship.friend, run])?;
+        }
+    }
+    trans.commit()
+}
+
+/// The user whose data the database holds, if any run has been recorded.
+pub fn read_username(conn: &Connection) -> rusqlite::Result<Option<String>> {
+    conn.query_row(
+        "SELECT username FROM runs ORDER BY id DESC LIMIT 1",
+        [],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+/// Reads every scrobble that hasn't since been deleted, newest first like
+/// Last.fm returns them. Rows written before migration 7 have no MBIDs or
+/// URL of their own and get those of their artist, album and track.
+pub fn read_scrobbles(conn: &Connection) -> rusqlite::Result<Vec<Track>> {
+    let mut statement = conn.prepare(
+        "SELECT tracks.name,
+                CASE WHEN scrobbles.url IS NULL THEN tracks.mbid ELSE scrobbles.mbid END,
+                COALESCE(scrobbles.url, tracks.url),
+                artists.name,
+                CASE WHEN scrobbles.url IS NULL THEN artists.mbid ELSE scrobbles.artist_mbid END,
+                albums.name,
+                CASE WHEN scrobbles.url IS NULL THEN albums.mbid ELSE scrobbles.album_mbid END,
+                scrobbles.timestamp, scrobbles.date_text, scrobbles.images
+            FROM scrobbles
+            JOIN tracks ON tracks.id = scrobbles.track_id
+            JOIN artists ON artists.id = tracks.artist_id
+            LEFT JOIN albums ON albums.id = scrobbles.album_id
+            WHERE scrobbles.deleted_run IS NULL
+            ORDER BY scrobbles.timestamp DESC, scrobbles.id
+        ",
+    )?;
+    let rows = statement.query_map([], |row| {
+        let datetime: Option<DateTime<Utc>> = row.get(7)?;
+        let album_name: Option<String> = row.get(5)?;
+        Ok(Track {
+            attributes: None,
+            artist: Artist {
+                name: row.get(3)?,
+                mbid: row.get(4)?,
+            },
+            album: match album_name {
+                Some(name) => Some(Album {
+                    name,
+                    mbid: row.get(6)?,
+                }),
+                None => None,
+            },
+            name: row.get(0)?,
+            image: images_from_json(row.get(9)?)?,
+            date: match datetime {
+                Some(datetime) => Some(Date {
+                    pretty_string: date_text_or_default(row.get(8)?, &datetime),
+                    datetime,
+                }),
+                None => None,
+            },
+            url: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+            mbid: row.get(1)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Reads the tracks that were playing during the most recent run.
+pub fn read_now_playing(conn: &Connection) -> rusqlite::Result<Vec<Track>> {
+    let mut statement = conn.prepare(
+        "SELECT name, mbid, url, artist, artist_mbid, album, album_mbid, images
+            FROM now_playing
+            WHERE run IS (SELECT MAX(run) FROM now_playing)
+            ORDER BY id
+        ",
+    )?;
+    let rows = statement.query_map([], |row| {
+        let album_name: Option<String> = row.get(5)?;
+        Ok(Track {
+            attributes: Some(TrackAttributes { now_playing: true }),
+            artist: Artist {
+                name: row.get(3)?,
+                mbid: row.get(4)?,
+            },
+            album: match album_name {
+                Some(name) => Some(Album {
+                    name,
+                    mbid: row.get(6)?,
+                }),
+                None => None,
+            },
+            name: row.get(0)?,
+            image: images_from_json(row.get(7)?)?,
+            date: None,
+            url: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+            mbid: row.get(1)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Reads the tracks that were loved as of the most recent run, most
+/// recently loved first like Last.fm returns them.
+pub fn read_loved_tracks(conn: &Connection) -> rusqlite::Result<Vec<LovedTrack>> {
+    let mut statement = conn.prepare(
+        "SELECT tracks.name,
+                CASE WHEN loved_tracks.url IS NULL THEN tracks.mbid ELSE loved_tracks.mbid END,
+                COALESCE(loved_tracks.url, tracks.url),
+                artists.name,
+                CASE WHEN loved_tracks.url IS NULL
+                    THEN artists.mbid ELSE loved_tracks.artist_mbid END,
+                CASE WHEN loved_tracks.url IS NULL
+                    THEN artists.url ELSE loved_tracks.artist_url END,
+                loved_tracks.date, loved_tracks.date_text, loved_tracks.images
+            FROM loved_tracks
+            JOIN tracks ON tracks.id = loved_tracks.track_id
+            JOIN artists ON artists.id = tracks.artist_id
+            WHERE loved_tracks.last_seen_run IS (SELECT MAX(last_seen_run) FROM loved_tracks)
+            ORDER BY loved_tracks.date DESC, loved_tracks.id
+        ",
+    )?;
+    let rows = statement.query_map([], |row| {
+        let datetime: Option<DateTime<Utc>> = row.get(6)?;
+        Ok(LovedTrack {
+            attributes: None,
+            artist: LovedArtist {
+                name: row.get(3)?,
+                mbid: row.get(4)?,
+                url: row.get(5)?,
+            },
+            name: row.get(0)?,
+            image: images_from_json(row.get(8)?)?,
+            date: match datetime {
+                Some(datetime) => Some(Date {
+                    pretty_string: date_text_or_default(row.get(7)?, &datetime),
+                    datetime,
+                }),
+                None => None,
+            },
+            url: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+            mbid: row.get(1)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Reads the friends the user had as of the most recent run.
+pub fn read_friends(conn: &Connection) -> rusqlite::Result<Vec<Friend>> {
+    let mut statement = conn.prepare(
+        "SELECT name, real_name, country, subscriber, registered, registered_text, url, images
+            FROM friends
+            WHERE last_seen_run IS (SELECT MAX(last_seen_run) FROM friends)
+            ORDER BY id
+        ",
+    )?;
+    let rows = statement.query_map([], |row| {
+        let registered: DateTime<Utc> = row.get(4)?;
+        Ok(Friend {
+            name: row.get(0)?,
+            image: images_from_json(row.get(7)?)?,
+            country: Some(row.get::<_, String>(2)?).filter(|country| !country.is_empty()),
+            url: row.get::<_, Option<String>>(6)?.unwrap_or_default(),
+            subscriber: row.get(3)?,
+            real_name: row.get(1)?,
+            registered: RegisterDate {
+                pretty_string: date_text_or_default(row.get(5)?, &registered),
+                datetime: registered,
+            },
+        })
+    })?;
+    rows.collect()
+}
+
+/// Reads every artist whose tags were looked up, with their tags. Databases
+/// not yet upgraded to list artists without tags only give those with some.
+pub fn read_artist_tags(conn: &Connection) -> rusqlite::Result<Vec<ArtistTags>> {
+    let query = if table_exists(conn, "tagged_artists")? {
+        "SELECT tagged_artists.artist, artists.genre, artist_tags.tag, artist_tags.weight
+            FROM tagged_artists
+            LEFT JOIN artists ON artists.name = tagged_artists.artist
+            LEFT JOIN artist_tags ON artist_tags.artist = tagged_artists.artist
+            ORDER BY tagged_artists.id, artist_tags.id
+        "
+    } else {
+        "SELECT artist_tags.artist, artists.genre, artist_tags.tag, artist_tags.weight
+            FROM artist_tags
+            LEFT JOIN artists ON artists.name = artist_tags.artist
+            ORDER BY artist_tags.id
+        "
+    };
+    let mut statement = conn.prepare(query)?;
+    let mut rows = statement.query([])?;
+
+    // Tags are stored one per row, so gather them back up by artist
+    let mut artist_tags: Vec<ArtistTags> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    while let Some(row) = rows.next()? {
+        let artist: String = row.get(0)?;
+        let position = *positions.entry(artist.clone()).or_insert_with(|| {
+            artist_tags.push(ArtistTags {
+                artist: artist.clone(),
+                genre: None,
+                tags: Vec::new(),
+            });
+            artist_tags.len() - 1
+        });
+        artist_tags[position].genre = row.get(1)?;
+        // Artists without tags have a single row without one
+        if let Some(name) = row.get(2)? {
+            artist_tags[position].tags.push(Tag {
+                name,
+                weight: row.get(3)?,
+            });
+        }
+    }
+    Ok(artist_tags)
+}
+
+pub fn read_friend_graph(conn: &Connection) -> rusqlite::Result<FriendGraph> {
+    let mut statement = conn.prepare(
+        "SELECT name, depth, real_name, country, subscriber, registered, registered_text,
+                url, images
+            FROM users
+            ORDER BY id
+        ",
+    )?;
+    let users = statement
+        .query_map([], |row| {
+            let name: String = row.get(0)?;
+            let registered: Option<DateTime<Utc>> = row.get(5)?;
+            // Only the backed up account itself has no profile
+            let profile = match registered {
+                Some(registered) => Some(Friend {
+                    name: name.clone(),
+                    image: images_from_json(row.get(8)?)?,
+                    country: row
+                        .get::<_, Option<String>>(3)?
+                        .filter(|country| !country.is_empty()),
+                    url: row.get::<_, Option<String>>(7)?.unwrap_or_default(),
+                    subscriber: row.get::<_, Option<bool>>(4)?.unwrap_or_default(),
+                    real_name: row.get(2)?,
+                    registered: RegisterDate {
+                        pretty_string: date_text_or_default(row.get(6)?, &registered),
+                        datetime: registered,
+                    },
+                }),
+                None => None,
+            };
+            Ok(User {
+                name,
+                depth: row.get(1)?,
+                profile,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<User>>>()?;
+
+    let mut statement = conn.prepare("SELECT user, friend FROM friendships ORDER BY id")?;
+    let friendships = statement
+        .query_map([], |row| {
+            Ok(Friendship {
+                user: row.get(0)?,
+                friend: row.get(1)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<Friendship>>>()?;
+
+    Ok(FriendGraph { users, friendships })
+}
+
+/// Reads the latest state of every dataset back out of the database.
+pub fn read_backup(conn: &Connection) -> anyhow::Result<Backup> {
+    Ok(Backup {
+        username: read_username(conn)?.unwrap_or_default(),
+        loved_tracks: read_loved_tracks(conn)?,
+        friends: read_friends(conn)?,
+        friend_graph: read_friend_graph(conn)?,
+        scrobbles: read_scrobbles(conn)?,
+        now_playing: read_now_playing(conn)?,
+        artist_tags: read_artist_tags(conn)?,
+        ..Backup::default()
+    })
+}
+
+/// Reads the latest state of every dataset out of the database `filename`,
+/// which may be compressed or encrypted. The file is never written to: older
+/// databases are brought up to date in a temporary copy so every table can
+/// be read.
+pub fn read_database(filename: &Path, decryption: &Decryption) -> anyhow::Result<Backup> {
+    let decoded = DecodedFile::new(filename, decryption)?;
+    let conn = open_db_read_only(&decoded.path().to_string_lossy())?;
+    if schema_version(&conn)? == MIGRATIONS.len() {
+        let backup = read_backup(&conn)?;
+        close_db(conn)?;
+        return Ok(backup);
+    }
+    close_db(conn)?;
+
+    let copy = DecodedFile::copy(filename, decryption)?;
+    let mut conn = open_db(&copy.path().to_string_lossy())?;
+    migrate(&mut conn)?;
+    let backup = read_backup(&conn)?;
+    close_db(conn)?;
+    Ok(backup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reading_an_old_database_leaves_it_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("old.db");
+        let conn = open_db(&path.to_string_lossy()).unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        close_db(conn).unwrap();
+        let before = std::fs::read(&path).unwrap();
+
+        read_database(&path, &Decryption::default()).unwrap();
+        read_database(&path, &Decryption::default()).unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+    }
+
+    #[test]
+    fn migrating_the_first_schema_keeps_every_row() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.execute_batch(
+            "INSERT INTO scrobbles (name, mbid, artist, artist_mbid, album, album_mbid, timestamp)
+                VALUES ('One', 'm-1', 'Artist', 'm-a', 'Album', 'm-b', '2021-01-01 00:00:00+00:00'),
+                    ('One', 'm-1', 'Artist', 'm-a', 'Album', 'm-b', '2021-01-02 00:00:00+00:00'),
+                    ('Two', NULL, 'Other', NULL, '', NULL, '2021-01-03 00:00:00+00:00');
+            INSERT INTO loved_tracks (name, mbid, artist, artist_mbid)
+                VALUES ('One', 'm-1', 'Artist', 'm-a'),
+                    ('Three', NULL, 'Loved only', NULL);
+            INSERT INTO friends (name, real_name, country, subscriber, registered)
+                VALUES ('friend', 'Friend', 'Norway', 0, '2010-01-01 00:00:00+00:00');",
+        )
+        .unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), 1);
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len());
+
+        let scrobbles: Vec<_> = read_scrobbles(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|track| {
+                (
+                    track.name,
+                    track.mbid,
+                    track.artist.name,
+                    track.artist.mbid,
+                    track.album.map(|album| (album.name, album.mbid)),
+                    track.date.unwrap().datetime.timestamp(),
+                )
+            })
+            .collect();
+        let one = |timestamp| {
+            (
+                "One".to_string(),
+                Some("m-1".to_string()),
+                "Artist".to_string(),
+                Some("m-a".to_string()),
+                Some(("Album".to_string(), Some("m-b".to_string()))),
+                timestamp,
+            )
+        };
+        assert_eq!(
+            scrobbles,
+            vec![
+                (
+                    "Two".to_string(),
+                    None,
+                    "Other".to_string(),
+                    None,
+                    Some((String::new(), None)),
+                    1609632000
+                ),
+                one(1609545600),
+                one(1609459200),
+            ]
+        );
+
+        let loved_tracks: Vec<_> = read_loved_tracks(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|track| (track.name, track.mbid, track.artist.name, track.artist.mbid))
+            .collect();
+        assert_eq!(loved_tracks.len(), 2);
+        assert!(loved_tracks.contains(&(
+            "One".to_string(),
+            Some("m-1".to_string()),
+            "Artist".to_string(),
+            Some("m-a".to_string())
+        )));
+        assert!(loved_tracks.contains(&(
+            "Three".to_string(),
+            None,
+            "Loved only".to_string(),
+            None
+        )));
+
+        let friends = read_friends(&conn).unwrap();
+        assert_eq!(friends.len(), 1);
+        assert_eq!(friends[0].name, "friend");
+        assert_eq!(friends[0].real_name.as_deref(), Some("Friend"));
+    }
+
+    #[test]
+    fn artist_tags_are_read_from_databases_not_yet_upgraded() {
+        let conn = Connection::open_in_memory().unwrap();
+        for migration in &MIGRATIONS[..7] {
+            conn.execute_batch(migration).unwrap();
+        }
+        conn.execute_batch("INSERT INTO artist_tags (artist, tag, weight) VALUES ('A', 'rock', 1)")
+            .unwrap();
+
+        let artist_tags = read_artist_tags(&conn).unwrap();
+        assert_eq!(artist_tags.len(), 1);
+        assert_eq!(artist_tags[0].tags[0].name, "rock");
+    }
+
+    #[test]
+    fn scrobbles_loved_tracks_and_artist_tags_round_trip_exactly() {
+        // The same track twice, once without any MBIDs and with another URL,
+        // so any merging of the two shows up
+        let scrobbles: Vec<Track> = serde_json::from_value(serde_json::json!([
+            {
+                "artist": {"name": "Artist", "mbid": "m-1"},
+                "album": {"name": "Album", "mbid": "m-2"},
+                "name": "Track",
+                "image": [{"url": "https://example.com/1.png", "size": "small"}],
+                "date": {"pretty_string": "02 Jan 2021, 00:00", "timestamp": 1609545600},
+                "url": "https://example.com/track",
+                "mbid": "m-3"
+            },
+            {
+                "artist": {"name": "Artist"},
+                "album": {"name": "Album"},
+                "name": "Track",
+                "image": [],
+                "date": {"pretty_string": "01 Jan 2021, 00:00", "timestamp": 1609459200},
+                "url": "https://example.com/track-elsewhere"
+            }
+        ]))
+        .unwrap();
+        let loved_tracks: Vec<LovedTrack> = serde_json::from_value(serde_json::json!([
+            {
+                "artist": {"name": "Artist", "url": "https://example.com/artist"},
+                "name": "Track",
+                "image": [],
+                "date": {"pretty_string": "03 Jan 2021, 00:00", "timestamp": 1609632000},
+                "url": "https://example.com/track-loved"
+            }
+        ]))
+        .unwrap();
+        // An artist without tags, listed before one with them
+        let artist_tags: Vec<ArtistTags> = serde_json::from_value(serde_json::json!([
+            {"artist": "Untagged", "tags": []},
+            {
+                "artist": "Artist",
+                "genre": "rock",
+                "tags": [{"name": "rock", "weight": 100}, {"name": "pop", "weight": 50}]
+            }
+        ]))
+        .unwrap();
+        let expected_scrobbles = serde_json::to_value(&scrobbles).unwrap();
+        let expected_loved_tracks = serde_json::to_value(&loved_tracks).unwrap();
+        let expected_artist_tags = serde_json::to_value(&artist_tags).unwrap();
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        let run = begin_run(&mut conn, "someone").unwrap();
+        insert_scrobbles(&mut conn, run, scrobbles).unwrap();
+        insert_loved_tracks(&mut conn, run, loved_tracks).unwrap();
+        insert_artist_tags(&mut conn, artist_tags).unwrap();
+
+        let backup = read_backup(&conn).unwrap();
+        assert_eq!(
+            serde_json::to_value(&backup.scrobbles).unwrap(),
+            expected_scrobbles
+        );
+        assert_eq!(
+            serde_json::to_value(&backup.loved_tracks).unwrap(),
+            expected_loved_tracks
+        );
+        assert_eq!(
+            serde_json::to_value(&backup.artist_tags).unwrap(),
+            expected_artist_tags
+        );
+    }
+
+    fn scrobble(name: &str, timestamp: i64) -> Track {
+        serde_json::from_value(serde_json::json!({
+            "artist": {"name": "Artist"},
+            "name": name,
+            "date": {"pretty_string": "", "timestamp": timestamp},
+            "url": ""
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn skipped_scrobbles_are_not_deleted() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        let run = begin_run(&mut conn, "someone").unwrap();
+        insert_scrobbles(&mut conn, run, vec![scrobble("A", 1), scrobble("B", 2)]).unwrap();
+
+        let run = begin_run(&mut conn, "someone").unwrap();
+        let fetched = [scrobble("A", 1)];
+        assert_eq!(
+            record_scrobble_changes(&mut conn, run, &fetched, false).unwrap(),
+            0
+        );
+        assert_eq!(
+            record_scrobble_changes(&mut conn, run, &fetched, true).unwrap(),
+            1
+        );
+    }
+
+    fn info(name: &str, album: Option<&str>) -> ScrobbleInfo {
+        ScrobbleInfo {
+            name: name.to_string(),
+            artist: "Artist".to_string(),
+            album: album.map(str::to_string),
+        }
+    }
+
+    fn at(timestamp: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(timestamp, 0).unwrap()
+    }
+
+    #[test]
+    fn diff_leaves_unchanged_scrobbles_alone() {
+        let stored = vec![
+            (1, at(1), info("A", Some("X"))),
+            (2, at(2), info("B", None)),
+        ];
+        let fetched = vec![(at(1), info("A", Some("X"))), (at(2), info("B", None))];
+        assert!(diff_scrobbles(&stored, &fetched).is_empty());
+    }
+
+    #[test]
+    fn diff_finds_edits() {
+        let stored = vec![
+            (1, at(1), info("A", Some("X"))),
+            (2, at(2), info("B", None)),
+        ];
+        // A moved to another album, B was renamed
+        let fetched = vec![(at(1), info("A", Some("Y"))), (at(2), info("C", None))];
+        let changes = diff_scrobbles(&stored, &fetched);
+
+        assert_eq!(changes.len(), 2);
+        let album = changes.iter().find(|change| change.id == 1).unwrap();
+        assert_eq!(album.kind, ChangeKind::Edited);
+        assert_eq!(album.new, Some(&info("A", Some("Y"))));
+        assert!(!album.missing);
+        let renamed = changes.iter().find(|change| change.id == 2).unwrap();
+        assert_eq!(renamed.kind, ChangeKind::Edited);
+        assert_eq!(renamed.old, &info("B", None));
+        assert_eq!(renamed.new, Some(&info("C", None)));
+        assert!(renamed.missing);
+    }
+
+    #[test]
+    fn diff_finds_deletions() {
+        let stored = vec![(1, at(1), info("A", None)), (2, at(2), info("B", None))];
+        let fetched = vec![(at(2), info("B", None))];
+        let changes = diff_scrobbles(&stored, &fetched);
+
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].id, 1);
+        assert_eq!(changes[0].kind, ChangeKind::Deleted);
+        assert_eq!(changes[0].new, None);
+        assert!(changes[0].missing);
+    }
+
+    #[test]
+    fn deleting_the_oldest_scrobbles_is_noticed() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        let run = begin_run(&mut conn, "someone").unwrap();
+        insert_scrobbles(&mut conn, run, vec![scrobble("A", 1), scrobble("B", 2)]).unwrap();
+
+        let run = begin_run(&mut conn, "someone").unwrap();
+        let fetched = [scrobble("B", 2)];
+        assert_eq!(
+            record_scrobble_changes(&mut conn, run, &fetched, true).unwrap(),
+            1
+        );
+        assert_eq!(read_scrobbles(&conn).unwrap().len(), 1);
+    }
+}
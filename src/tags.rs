@@ -0,0 +1,169 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::api::*;
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ArtistTags {
+    pub artist: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub genre: Option<String>,
+    pub tags: Vec<Tag>,
+}
+
+/// Remembers the top tags of every artist looked up so far, so that each
+/// artist only costs a single request however many accounts and friends
+/// played them. Can be seeded with tags fetched by earlier runs. Lookups that
+/// fail aren't remembered, so they're tried again the next time.
+#[derive(Default)]
+pub struct TagCache {
+    tags: HashMap<String, Vec<Tag>>,
+    failures: usize,
+}
+
+impl TagCache {
+    pub fn new() -> Self {
+        TagCache::default()
+    }
+
+    /// Remembers tags fetched earlier, so that they aren't fetched again.
+    pub fn preload(&mut self, artist_tags: Vec<ArtistTags>) {
+        for artist_tags in artist_tags {
+            self.tags
+                .insert(artist_tags.artist.to_lowercase(), artist_tags.tags);
+        }
+    }
+
+    pub fn contains(&self, artist: &str) -> bool {
+        self.tags.contains_key(&artist.to_lowercase())
+    }
+
+    /// How many lookups have failed so far, which can be compared before and
+    /// after looking up a backup's artists to tell whether any were missed.
+    pub fn failures(&self) -> usize {
+        self.failures
+    }
+
+    /// The tags of `artist`, fetching them unless they're already known.
+    /// Returns `None` if they couldn't be fetched.
+    pub fn lookup(&mut self, client: &mut LastFM, artist: &str) -> Option<&[Tag]> {
+        let key = artist.to_lowercase();
+        if !self.tags.contains_key(&key) {
+            match client.artist_top_tags(artist) {
+                Ok(tags) => {
+                    self.tags.insert(key.clone(), tags);
+                }
+                Err(_) => {
+                    log::warn!("Failed to fetch tags for {}. Continuing...", artist);
+                    self.failures += 1;
+                    return None;
+                }
+            }
+        }
+        self.tags.get(&key).map(Vec::as_slice)
+    }
+}
+
+/// Reads a genre allowlist, one tag per line. Blank lines and lines starting
+/// with `#` are ignored.
+pub fn read_allowlist<P: AsRef<Path>>(filename: P) -> anyhow::Result<HashSet<String>> {
+    let contents = fs::read_to_string(filename)?;
+    Ok(contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_lowercase())
+        .collect())
+}
+
+/// Picks the heaviest tag, skipping tags missing from the allowlist if one
+/// was supplied.
+pub fn pick_genre(tags: &[Tag], allowlist: Option<&HashSet<String>>) -> Option<String> {
+    tags.iter()
+        .filter(|tag| match allowlist {
+            Some(allowlist) => allowlist.contains(&tag.name.to_lowercase()),
+            None => true,
+        })
+        .min_by_key(|tag| Reverse(tag.weight))
+        .map(|tag| tag.name.clone())
+}
+
+/// Looks up the tags of every one of `artists`. Artists whose tags couldn't
+/// be fetched are left out, rather than passed off as having none.
+pub fn artist_tags<'a>(
+    client: &mut LastFM,
+    cache: &mut TagCache,
+    artists: impl IntoIterator<Item = &'a str>,
+    allowlist: Option<&HashSet<String>>,
+) -> Vec<ArtistTags> {
+    // Dedupe case-insensitively, keeping the first spelling we come across
+    let mut seen: HashSet<String> = HashSet::new();
+    let artists: Vec<&str> = artists
+        .into_iter()
+        .filter(|artist| seen.insert(artist.to_lowercase()))
+        .collect();
+
+    let mut artist_tags: Vec<ArtistTags> = Vec::new();
+    for (i, artist) in artists.iter().enumerate() {
+        if !cache.contains(artist) {
+            log::info!("Requesting tags for artist {} of {}", i + 1, artists.len());
+        }
+        let tags = match cache.lookup(client, artist) {
+            Some(tags) => tags.to_vec(),
+            None => continue,
+        };
+        artist_tags.push(ArtistTags {
+            artist: artist.to_string(),
+            genre: pick_genre(&tags, allowlist),
+            tags,
+        });
+    }
+    artist_tags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preloaded_artists_are_not_fetched_again() {
+        // Replaying no pages at all, so any request would fail
+        let mut client = LastFM::replay(HashMap::new());
+        let mut cache = TagCache::new();
+        let rock = Tag {
+            name: "rock".to_string(),
+            weight: 100,
+        };
+        cache.preload(vec![ArtistTags {
+            artist: "Artist".to_string(),
+            genre: None,
+            tags: vec![rock.clone()],
+        }]);
+
+        let artist_tags = artist_tags(&mut client, &mut cache, ["artist", "Artist"], None);
+        assert_eq!(
+            artist_tags,
+            vec![ArtistTags {
+                artist: "artist".to_string(),
+                genre: Some("rock".to_string()),
+                tags: vec![rock],
+            }]
+        );
+        assert_eq!(cache.failures(), 0);
+    }
+
+    #[test]
+    fn failed_lookups_are_left_out_and_not_remembered() {
+        let mut client = LastFM::replay(HashMap::new());
+        let mut cache = TagCache::new();
+
+        let artist_tags = artist_tags(&mut client, &mut cache, ["Artist"], None);
+        assert!(artist_tags.is_empty());
+        assert_eq!(cache.failures(), 1);
+        assert!(!cache.contains("Artist"));
+    }
+}
@@ -0,0 +1,375 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::api::{Friend, LovedTrack, Track};
+use super::archive::{is_archive, read_archive};
+use super::encryption::Decryption;
+use super::graph::{Friendship, User};
+use super::raw::read_raw_pages;
+use super::serialize::{self, Encoding};
+use super::sink::{FileFormat, WrittenFile, DATASETS};
+use super::sql::read_database;
+use super::tags::ArtistTags;
+
+/// Bumped whenever the layout of a run manifest changes in a way older
+/// versions of hatchery can't read.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// A file listed in a run manifest.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FileInfo {
+    /// Where the file is, relative to the manifest
+    pub file: String,
+    pub format: FileFormat,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dataset: Option<String>,
+    /// How many records the file holds, if it holds a single dataset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub count: Option<usize>,
+    /// Checksum of the file as it is on disk, so after any compression and
+    /// encryption. Left out for mutable files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Whether later runs change the file, like a database synced into with
+    /// `--sync`, so that only whether it still parses can be checked
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub mutable: bool,
+}
+
+/// Lists every file a run wrote, written next to them as
+/// `{basename}-manifest.json` by default.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RunManifest {
+    pub format_version: u32,
+    pub username: String,
+    /// The account whose run backed this user up as one of its friends, with
+    /// `--include-friends`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub friend_of: Option<String>,
+    pub finished: DateTime<Utc>,
+    pub hatchery_version: String,
+    /// Whether every dataset was written
+    pub complete: bool,
+    pub files: Vec<FileInfo>,
+}
+
+pub fn sha256_file(filename: &Path) -> anyhow::Result<String> {
+    let mut file = File::open(filename)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Writes a manifest of `files` to `filename`. `friend_of` is the account
+/// whose run wrote them, if `username` was only backed up as its friend.
+pub fn write_manifest(
+    filename: &Path,
+    username: &str,
+    friend_of: Option<&str>,
+    files: &[WrittenFile],
+    complete: bool,
+) -> anyhow::Result<()> {
+    let directory = filename.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut infos = Vec::new();
+    for written in files {
+        // Files outside the manifest's directory are listed in full
+        let file = match written.path.strip_prefix(directory) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => fs::canonicalize(&written.path)?,
+        };
+        infos.push(FileInfo {
+            file: file.to_string_lossy().into_owned(),
+            format: written.format,
+            dataset: written.dataset.clone(),
+            count: written.count,
+            sha256: if written.mutable {
+                None
+            } else {
+                Some(sha256_file(&written.path)?)
+            },
+            mutable: written.mutable,
+        });
+    }
+
+    let manifest = RunManifest {
+        format_version: FORMAT_VERSION,
+        username: username.to_string(),
+        friend_of: friend_of.map(str::to_string),
+        finished: Utc::now(),
+        hatchery_version: env!("CARGO_PKG_VERSION").to_string(),
+        complete,
+        files: infos,
+    };
+    // Left plain, so checksums can be checked without any keys
+    serialize::write_json(
+        filename.to_string_lossy().into_owned(),
+        &manifest,
+        true,
+        &Encoding::default(),
+    )
+}
+
+/// Reads `filename` as a run manifest, failing if it isn't one.
+pub fn read_manifest(filename: &Path) -> anyhow::Result<RunManifest> {
+    let manifest: RunManifest = serde_json::from_reader(BufReader::new(File::open(filename)?))?;
+    if manifest.format_version > FORMAT_VERSION {
+        return Err(anyhow!(
+            "Manifest was written by hatchery {}, which is newer than this version",
+            manifest.hatchery_version
+        ));
+    }
+    Ok(manifest)
+}
+
+/// Checks every file listed in the manifest at `filename` against its
+/// checksum and record count, then re-parses it. Logs every file as it's
+/// checked, and fails if any of them are broken.
+pub fn verify_manifest(
+    filename: &Path,
+    manifest: &RunManifest,
+    decryption: &Decryption,
+) -> anyhow::Result<()> {
+    let directory = filename.parent().unwrap_or_else(|| Path::new(""));
+    let mut failed = 0;
+    for info in &manifest.files {
+        match verify_listed(&directory.join(&info.file), info, decryption) {
+            Ok(()) => log::info!("{}: OK", info.file),
+            Err(e) => {
+                failed += 1;
+                log::error!("{}: {}", info.file, e);
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(anyhow!(
+            "{} of {} files failed verification",
+            failed,
+            manifest.files.len()
+        ));
+    }
+    Ok(())
+}
+
+fn verify_listed(filename: &Path, info: &FileInfo, decryption: &Decryption) -> anyhow::Result<()> {
+    if !filename.exists() {
+        return Err(anyhow!("Missing"));
+    }
+    if let Some(sha256) = &info.sha256 {
+        if sha256_file(filename)? != *sha256 {
+            return Err(anyhow!("Doesn't match its checksum"));
+        }
+    }
+    let count = parse_file(filename, info.format, info.dataset.as_deref(), decryption)?;
+    match (count, info.count) {
+        (Some(count), Some(expected)) if count != expected => Err(anyhow!(
+            "Holds {} records, but the manifest says {}",
+            count,
+            expected
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Works out the format of a backup file that has no manifest, and the
+/// dataset it holds from its name if it holds only one.
+pub fn identify(
+    filename: &Path,
+    decryption: &Decryption,
+) -> anyhow::Result<(FileFormat, Option<String>)> {
+    if is_archive(filename, decryption)? {
+        return Ok((FileFormat::Archive, None));
+    }
+
+    let plain = serialize::strip_encoding(filename);
+    let format = match plain.extension().and_then(|extension| extension.to_str()) {
+        Some("db") => return Ok((FileFormat::Sqlite, None)),
+        Some("parquet") => return Ok((FileFormat::Parquet, None)),
+        Some("json") => FileFormat::Json,
+        Some("ndjson") if plain.to_string_lossy().ends_with("-raw.ndjson") => {
+            return Ok((FileFormat::Raw, None))
+        }
+        Some("ndjson") => FileFormat::Ndjson,
+        Some("csv") => FileFormat::Csv,
+        _ => {
+            return Err(anyhow!(
+                "Can't tell what kind of backup {} is",
+                filename.display()
+            ))
+        }
+    };
+    let stem = plain
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    // The last dataset named wins, and the longest at the same place, as
+    // `-friends` is also the start of `-friendships`
+    let dataset = DATASETS
+        .iter()
+        .filter_map(|dataset| {
+            Some((
+                stem.rfind(&format!("-{}", dataset))?,
+                dataset.len(),
+                dataset,
+            ))
+        })
+        .max()
+        .map(|(_, _, dataset)| dataset)
+        .ok_or_else(|| anyhow!("Can't tell which dataset {} holds", filename.display()))?;
+    Ok((format, Some(dataset.to_string())))
+}
+
+/// Re-parses `filename` into the API types, returning how many records it
+/// holds if it holds a single dataset.
+///
+/// CSV rows are flattened, so they're only checked to all have the same
+/// columns as the header. Parquet files are read back in full.
+pub fn parse_file(
+    filename: &Path,
+    format: FileFormat,
+    dataset: Option<&str>,
+    decryption: &Decryption,
+) -> anyhow::Result<Option<usize>> {
+    match format {
+        FileFormat::Archive => {
+            read_archive(filename, decryption)?;
+            Ok(None)
+        }
+        FileFormat::Sqlite => {
+            read_database(filename, decryption)?;
+            Ok(None)
+        }
+        FileFormat::Raw => {
+            let (_, pages) = read_raw_pages(filename, decryption)?;
+            Ok(Some(pages.len()))
+        }
+        #[cfg(feature = "parquet")]
+        FileFormat::Parquet => super::columnar::count_rows(filename).map(Some),
+        #[cfg(not(feature = "parquet"))]
+        FileFormat::Parquet => {
+            log::warn!(
+                "{} can't be read without the parquet feature, so only its checksum was checked",
+                filename.display()
+            );
+            Ok(None)
+        }
+        FileFormat::Csv => {
+            let mut reader = csv::Reader::from_reader(serialize::open(filename, decryption)?);
+            let mut count = 0;
+            for record in reader.records() {
+                record.map_err(|e| anyhow!("Row {}: {}", count + 1, e))?;
+                count += 1;
+            }
+            Ok(Some(count))
+        }
+        FileFormat::Json | FileFormat::Ndjson => {
+            let dataset = dataset.ok_or_else(|| anyhow!("No dataset given"))?;
+            let reader = serialize::open(filename, decryption)?;
+            let count = match dataset {
+                "scrobbles" | "now_playing" => parse_records::<Track>(format, reader)?,
+                "loved_tracks" => parse_records::<LovedTrack>(format, reader)?,
+                "friends" => parse_records::<Friend>(format, reader)?,
+                "users" => parse_records::<User>(format, reader)?,
+                "friendships" => parse_records::<Friendship>(format, reader)?,
+                "artist_tags" => parse_records::<ArtistTags>(format, reader)?,
+                _ => return Err(anyhow!("Unknown dataset {}", dataset)),
+            };
+            Ok(Some(count))
+        }
+    }
+}
+
+fn parse_records<T: DeserializeOwned>(
+    format: FileFormat,
+    reader: Box<dyn Read>,
+) -> anyhow::Result<usize> {
+    let reader = BufReader::new(reader);
+    if format == FileFormat::Json {
+        let records: Vec<T> = serde_json::from_reader(reader)?;
+        return Ok(records.len());
+    }
+
+    let mut count = 0;
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        serde_json::from_str::<T>(&line).map_err(|e| anyhow!("Line {}: {}", i + 1, e))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a run of two scrobbles and its manifest to `dir`, returning
+    /// the manifest's name.
+    fn write_run(dir: &Path) -> std::path::PathBuf {
+        let path = dir.join("hatchery-scrobbles.ndjson");
+        let scrobble = |name: &str, timestamp: i64| {
+            serde_json::json!({
+                "artist": {"name": "Artist"},
+                "name": name,
+                "date": {"pretty_string": "", "timestamp": timestamp},
+                "url": ""
+            })
+        };
+        serialize::write_ndjson(
+            path.to_string_lossy().into_owned(),
+            &[scrobble("A", 1), scrobble("B", 2)],
+            &Encoding::default(),
+        )
+        .unwrap();
+
+        let manifest = dir.join("hatchery-manifest.json");
+        let written = WrittenFile {
+            path,
+            format: FileFormat::Ndjson,
+            dataset: Some("scrobbles".to_string()),
+            count: Some(2),
+            mutable: false,
+        };
+        write_manifest(&manifest, "someone", None, &[written], true).unwrap();
+        manifest
+    }
+
+    #[test]
+    fn untouched_runs_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = write_run(dir.path());
+
+        let manifest = read_manifest(&filename).unwrap();
+        assert_eq!(manifest.files[0].file, "hatchery-scrobbles.ndjson");
+        verify_manifest(&filename, &manifest, &Decryption::default()).unwrap();
+    }
+
+    #[test]
+    fn a_flipped_byte_fails_the_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = write_run(dir.path());
+        let manifest = read_manifest(&filename).unwrap();
+
+        // Renames a scrobble, so that the file still parses
+        let scrobbles = dir.path().join("hatchery-scrobbles.ndjson");
+        let mut data = fs::read(&scrobbles).unwrap();
+        let i = data.windows(5).position(|w| w == b"\"A\",\"").unwrap();
+        data[i + 1] ^= 0x02;
+        fs::write(&scrobbles, data).unwrap();
+
+        let info = &manifest.files[0];
+        let e = verify_listed(&scrobbles, info, &Decryption::default()).unwrap_err();
+        assert_eq!(e.to_string(), "Doesn't match its checksum");
+        assert!(verify_manifest(&filename, &manifest, &Decryption::default()).is_err());
+    }
+}